@@ -1,7 +1,9 @@
 mod utils;
 
 use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
-use rtree_performance::{PackedRTree, PackedRTreeUnsorted};
+use rtree_performance::{
+    PackedRTree, PackedRTreeLsm, PackedRTreeSoa, PackedRTreeUnsorted, RStarTree,
+};
 
 use utils::{get_positions_list, make_rectangles_list};
 
@@ -37,6 +39,15 @@ pub fn construction_benchmark(c: &mut Criterion) {
             //         })
             //     },
             // );
+            group.bench_with_input(
+                BenchmarkId::new(format!("packed_rtree_soa_build.{}", poly_idx), degree),
+                degree,
+                |b, &d| {
+                    b.iter(|| {
+                        PackedRTreeSoa::new(d, rectangles);
+                    })
+                },
+            );
             group.bench_with_input(
                 BenchmarkId::new(format!("packed_rtree_hilbert_build.{}", poly_idx), degree),
                 degree,
@@ -46,12 +57,52 @@ pub fn construction_benchmark(c: &mut Criterion) {
                     })
                 },
             );
+            group.bench_with_input(
+                BenchmarkId::new(format!("packed_rtree_morton_build.{}", poly_idx), degree),
+                degree,
+                |b, &d| {
+                    b.iter(|| {
+                        PackedRTree::new_morton(d, rectangles);
+                    })
+                },
+            );
+            group.bench_with_input(
+                BenchmarkId::new(format!("packed_rtree_str_build.{}", poly_idx), degree),
+                degree,
+                |b, &d| {
+                    b.iter(|| {
+                        PackedRTree::new_str(d, rectangles);
+                    })
+                },
+            );
             group.bench_with_input(
                 BenchmarkId::new(format!("packed_rtree_omt_build.{}", poly_idx), degree),
                 degree,
-                |b, &_d| {
+                |b, &d| {
+                    b.iter(|| {
+                        PackedRTree::new_omt(d, rectangles);
+                    })
+                },
+            );
+            group.bench_with_input(
+                BenchmarkId::new(format!("rstar_tree_build.{}", poly_idx), degree),
+                degree,
+                |b, &d| {
+                    b.iter(|| {
+                        RStarTree::new(d, rectangles);
+                    })
+                },
+            );
+            group.bench_with_input(
+                BenchmarkId::new(format!("packed_rtree_lsm_build.{}", poly_idx), degree),
+                degree,
+                |b, &d| {
                     b.iter(|| {
-                        PackedRTree::new_omt(rectangles);
+                        let mut lsm = PackedRTreeLsm::with_degree(d);
+                        for batch in rectangles.chunks(1024) {
+                            lsm.append(batch);
+                        }
+                        lsm.finish_merges();
                     })
                 },
             );
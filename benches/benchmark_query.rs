@@ -2,7 +2,10 @@ mod utils;
 
 use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
 
-use rtree_performance::{PackedRTree, PackedRTreeAutoSimd, PackedRTreeUnsorted, RTree, Rectangle};
+use rtree_performance::{
+    PackedRTree, PackedRTreeAutoSimd, PackedRTreeF32, PackedRTreeQuantized, PackedRTreeSimd,
+    PackedRTreeSoa, PackedRTreeSortedAutoSimd, PackedRTreeUnsorted, RStarTree, RTree, Rectangle,
+};
 use utils::{get_positions_list, get_random_points, make_rectangles_list};
 
 pub fn query_benchmark(c: &mut Criterion) {
@@ -17,10 +20,8 @@ pub fn query_benchmark(c: &mut Criterion) {
     let mut group = c.benchmark_group(format!("query_{}", name));
 
     for (poly_idx, rectangles) in rectangles_list.iter().enumerate() {
-        let query_rects: Vec<_> = get_random_points(Rectangle::of(rectangles), 1000, 342)
-            .into_iter()
-            .map(|p| Rectangle::new(p, p))
-            .collect();
+        let query_points = get_random_points(Rectangle::of(rectangles), 1000, 342);
+        let query_rects: Vec<_> = query_points.iter().map(|&p| Rectangle::new(p, p)).collect();
         println!("Polygon {} has {} segments.", poly_idx, rectangles.len());
         // for &degree in [8, 16].iter() {
         for &degree in [8].iter() {
@@ -40,6 +41,20 @@ pub fn query_benchmark(c: &mut Criterion) {
                 },
             );
 
+            group.bench_function(
+                BenchmarkId::new(
+                    format!("packed_rtree_unsorted_point_query.{}", poly_idx),
+                    degree,
+                ),
+                |b| {
+                    b.iter(|| {
+                        for &point in &query_points {
+                            black_box(rtree_native.query_point(point));
+                        }
+                    })
+                },
+            );
+
             // group.bench_function(
             //     BenchmarkId::new(format!("packed_rtree_auto_simd_query.{}", poly_idx), degree),
             //     |b| {
@@ -52,6 +67,54 @@ pub fn query_benchmark(c: &mut Criterion) {
             //     },
             // );
 
+            let rtree_soa = PackedRTreeSoa::new(degree, rectangles);
+            group.bench_function(
+                BenchmarkId::new(format!("packed_rtree_soa_query.{}", poly_idx), degree),
+                |b| {
+                    b.iter(|| {
+                        for rect in &query_rects {
+                            black_box(rtree_soa.query_rect(rect));
+                        }
+                    })
+                },
+            );
+
+            let rtree_f32 = PackedRTreeF32::new(degree, rectangles.clone());
+            group.bench_function(
+                BenchmarkId::new(format!("packed_rtree_f32_query.{}", poly_idx), degree),
+                |b| {
+                    b.iter(|| {
+                        for rect in &query_rects {
+                            black_box(rtree_f32.query_rect(rect));
+                        }
+                    })
+                },
+            );
+
+            let rtree_quantized = PackedRTreeQuantized::new(degree, rectangles.clone());
+            group.bench_function(
+                BenchmarkId::new(format!("packed_rtree_quantized_query.{}", poly_idx), degree),
+                |b| {
+                    b.iter(|| {
+                        for rect in &query_rects {
+                            black_box(rtree_quantized.query_rect(rect));
+                        }
+                    })
+                },
+            );
+
+            let rtree_simd = PackedRTreeSimd::new(degree, rectangles);
+            group.bench_function(
+                BenchmarkId::new(format!("packed_rtree_simd_query.{}", poly_idx), degree),
+                |b| {
+                    b.iter(|| {
+                        for rect in &query_rects {
+                            black_box(rtree_simd.query_rect(rect));
+                        }
+                    })
+                },
+            );
+
             group.bench_function(
                 BenchmarkId::new(format!("packed_rtree_hilbert_query.{}", poly_idx), degree),
                 |b| {
@@ -64,7 +127,76 @@ pub fn query_benchmark(c: &mut Criterion) {
                 },
             );
 
-            let mut rtree_omt = PackedRTree::new_omt(rectangles);
+            group.bench_function(
+                BenchmarkId::new(
+                    format!("packed_rtree_hilbert_point_query.{}", poly_idx),
+                    degree,
+                ),
+                |b| {
+                    b.iter(|| {
+                        for &point in &query_points {
+                            black_box(rtree_hilbert.query_point(point));
+                        }
+                    })
+                },
+            );
+
+            group.bench_function(
+                BenchmarkId::new(
+                    format!("packed_rtree_hilbert_batch_query.{}", poly_idx),
+                    degree,
+                ),
+                |b| b.iter(|| black_box(rtree_hilbert.query_rect_batch(&query_rects))),
+            );
+
+            group.bench_function(
+                BenchmarkId::new(
+                    format!("packed_rtree_hilbert_sorted_batch_query.{}", poly_idx),
+                    degree,
+                ),
+                |b| b.iter(|| black_box(rtree_hilbert.query_rect_batch_sorted(&query_rects))),
+            );
+
+            let rtree_sorted_auto_simd = PackedRTreeSortedAutoSimd::new_hilbert(degree, rectangles);
+            group.bench_function(
+                BenchmarkId::new(
+                    format!("packed_rtree_hilbert_auto_simd_query.{}", poly_idx),
+                    degree,
+                ),
+                |b| {
+                    b.iter(|| {
+                        for rect in &query_rects {
+                            black_box(rtree_sorted_auto_simd.query_rect(rect));
+                        }
+                    })
+                },
+            );
+
+            let rtree_morton = PackedRTree::new_morton(degree, rectangles);
+            group.bench_function(
+                BenchmarkId::new(format!("packed_rtree_morton_query.{}", poly_idx), degree),
+                |b| {
+                    b.iter(|| {
+                        for rect in &query_rects {
+                            black_box(rtree_morton.query_rect(rect));
+                        }
+                    })
+                },
+            );
+
+            let rtree_str = PackedRTree::new_str(degree, rectangles);
+            group.bench_function(
+                BenchmarkId::new(format!("packed_rtree_str_query.{}", poly_idx), degree),
+                |b| {
+                    b.iter(|| {
+                        for rect in &query_rects {
+                            black_box(rtree_str.query_rect(rect));
+                        }
+                    })
+                },
+            );
+
+            let mut rtree_omt = PackedRTree::new_omt(degree, rectangles);
             group.bench_function(
                 BenchmarkId::new(format!("packed_rtree_omt_query.{}", poly_idx), degree),
                 |b| {
@@ -76,6 +208,18 @@ pub fn query_benchmark(c: &mut Criterion) {
                     })
                 },
             );
+
+            let rtree_rstar = RStarTree::new(degree, rectangles);
+            group.bench_function(
+                BenchmarkId::new(format!("rstar_tree_query.{}", poly_idx), degree),
+                |b| {
+                    b.iter(|| {
+                        for rect in &query_rects {
+                            black_box(rtree_rstar.query_rect(rect));
+                        }
+                    })
+                },
+            );
         }
     }
 
@@ -0,0 +1,18 @@
+use crate::{Coordinate, Rectangle};
+
+/**
+ * A space-filling curve over a rectangle, used to order items for packing.
+ *
+ * Positions that are close on the curve should be close in space.  With the
+ * parallel feature, keys are computed and sorted on multiple threads, so the
+ * curve must be Sync and its keys Send, whether or not the feature is on.
+ */
+pub trait SpaceFillingCurve: Sync {
+    type Key: Ord + Copy + Send;
+
+    /// Build the curve covering envelope, which may be empty.
+    fn from_envelope(envelope: Rectangle) -> Self;
+
+    /// The position of a coordinate along the curve.
+    fn key(&self, position: Coordinate) -> Self::Key;
+}
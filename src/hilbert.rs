@@ -1,4 +1,4 @@
-use crate::{Coordinate, Rectangle};
+use crate::{Coordinate, Rectangle, SpaceFillingCurve};
 
 pub struct Hilbert {
     env: Rectangle,
@@ -135,6 +135,128 @@ impl Hilbert {
     }
 }
 
+impl SpaceFillingCurve for Hilbert {
+    type Key = u32;
+
+    fn from_envelope(envelope: Rectangle) -> Self {
+        Hilbert::new(envelope)
+    }
+
+    fn key(&self, position: Coordinate) -> u32 {
+        self.hilbert(position)
+    }
+}
+
+/**
+ * Like Hilbert, but with 32 bits per axis and u64 keys.
+ *
+ * This keeps nearby positions distinct for large envelopes with dense
+ * clusters, at the cost of larger keys.
+ */
+pub struct Hilbert64 {
+    x_min: f64,
+    y_min: f64,
+    x_scale: f64,
+    y_scale: f64,
+}
+
+impl Hilbert64 {
+    #[allow(clippy::float_cmp)]
+    pub fn new(env: Rectangle) -> Self {
+        let hilbert_max = u32::MAX as f64;
+        let (x_min, x_scale) = if env.is_empty() || env.x_min == env.x_max {
+            (0., 0.)
+        } else {
+            (env.x_min, hilbert_max / (env.x_max - env.x_min))
+        };
+        let (y_min, y_scale) = if env.is_empty() || env.y_min == env.y_max {
+            (0., 0.)
+        } else {
+            (env.y_min, hilbert_max / (env.y_max - env.y_min))
+        };
+        Hilbert64 {
+            x_min,
+            y_min,
+            x_scale,
+            y_scale,
+        }
+    }
+
+    /// Returns the hilbert index of position in the rectangle, without bounds checks.
+    pub fn hilbert(&self, position: Coordinate) -> u64 {
+        let x = self.x_scale * (position.x - self.x_min);
+        let y = self.y_scale * (position.y - self.y_min);
+        Self::hilbert_normalized(x.floor() as u32, y.floor() as u32)
+    }
+
+    /// The 32-bit extension of Hilbert::hilbert_normalized.
+    #[allow(non_snake_case)]
+    #[allow(clippy::many_single_char_names)]
+    pub fn hilbert_normalized(x: u32, y: u32) -> u64 {
+        let x = x as u64;
+        let y = y as u64;
+        let mask = 0xFFFF_FFFF;
+
+        let mut a = x ^ y;
+        let mut b = mask ^ a;
+        let mut c = mask ^ (x | y);
+        let mut d = x & (y ^ mask);
+
+        let mut A = a | (b >> 1);
+        let mut B = (a >> 1) ^ a;
+        let mut C = ((c >> 1) ^ (b & (d >> 1))) ^ c;
+        let mut D = ((a & (c >> 1)) ^ (d >> 1)) ^ d;
+
+        for &shift in [2, 4, 8].iter() {
+            a = A;
+            b = B;
+            c = C;
+            d = D;
+            A = (a & (a >> shift)) ^ (b & (b >> shift));
+            B = (a & (b >> shift)) ^ (b & ((a ^ b) >> shift));
+            C ^= (a & (c >> shift)) ^ (b & (d >> shift));
+            D ^= (b & (c >> shift)) ^ ((a ^ b) & (d >> shift));
+        }
+
+        a = A;
+        b = B;
+        c = C;
+        d = D;
+        C ^= (a & (c >> 16)) ^ (b & (d >> 16));
+        D ^= (b & (c >> 16)) ^ ((a ^ b) & (d >> 16));
+
+        a = C ^ (C >> 1);
+        b = D ^ (D >> 1);
+
+        let i0 = x ^ y;
+        let i1 = b | (mask ^ (i0 | a));
+
+        (interleave(i1) << 1) | interleave(i0)
+    }
+}
+
+impl SpaceFillingCurve for Hilbert64 {
+    type Key = u64;
+
+    fn from_envelope(envelope: Rectangle) -> Self {
+        Hilbert64::new(envelope)
+    }
+
+    fn key(&self, position: Coordinate) -> u64 {
+        self.hilbert(position)
+    }
+}
+
+/// Spread the lower 32 bits of x into the even bits of the result.
+fn interleave(mut x: u64) -> u64 {
+    x = (x | (x << 16)) & 0x0000_FFFF_0000_FFFF;
+    x = (x | (x << 8)) & 0x00FF_00FF_00FF_00FF;
+    x = (x | (x << 4)) & 0x0F0F_0F0F_0F0F_0F0F;
+    x = (x | (x << 2)) & 0x3333_3333_3333_3333;
+    x = (x | (x << 1)) & 0x5555_5555_5555_5555;
+    x
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -197,4 +319,68 @@ mod tests {
         assert!(hi2 < hi3);
         assert!(hi3 < hi4);
     }
+
+    #[test]
+    fn hilbert64_matches_hilbert_at_coarse_scale() {
+        for &(x, y) in [
+            (0, 0),
+            (1, 0),
+            (12345, 67890 % 65536),
+            (65535, 65535),
+            (300, 40000),
+        ]
+        .iter()
+        {
+            let h32 = Hilbert::hilbert_normalized(x, y);
+            let h64 = Hilbert64::hilbert_normalized(x << 16, y << 16);
+            assert_eq!(h64 >> 32, h32 as u64);
+        }
+    }
+
+    #[test]
+    fn hilbert64_is_continuous() {
+        // The first 4^k keys fill the 2^k x 2^k square at the origin.
+        let mut cells: Vec<(u64, u32, u32)> = Vec::new();
+        for x in 0..16 {
+            for y in 0..16 {
+                cells.push((Hilbert64::hilbert_normalized(x, y), x, y));
+            }
+        }
+        cells.sort_unstable();
+        for (expected, &(h, _, _)) in cells.iter().enumerate() {
+            assert_eq!(h, expected as u64);
+        }
+        for pair in cells.windows(2) {
+            let (_, x0, y0) = pair[0];
+            let (_, x1, y1) = pair[1];
+            let dist = (x0 as i64 - x1 as i64).abs() + (y0 as i64 - y1 as i64).abs();
+            assert_eq!(dist, 1);
+        }
+    }
+
+    #[test]
+    fn hilbert64_distinguishes_close_positions() {
+        let total_rect = Rectangle::new((0., 0.).into(), (1., 1.).into());
+        let h = Hilbert::new(total_rect);
+        let h64 = Hilbert64::new(total_rect);
+        let p1 = Coordinate::new(0.5, 0.5);
+        let p2 = Coordinate::new(0.5 + 1e-7, 0.5);
+        assert_eq!(h.hilbert(p1), h.hilbert(p2));
+        assert_ne!(h64.hilbert(p1), h64.hilbert(p2));
+    }
+
+    #[test]
+    fn hilbert64_ordering() {
+        let total_rect = Rectangle::new((0., 0.).into(), (4., 4.).into());
+        let h = Hilbert64::new(total_rect);
+        let hi0 = h.hilbert(Coordinate::new(0., 0.));
+        let hi1 = h.hilbert(Coordinate::new(1., 1.));
+        let hi2 = h.hilbert(Coordinate::new(1., 3.));
+        let hi3 = h.hilbert(Coordinate::new(3., 3.));
+        let hi4 = h.hilbert(Coordinate::new(3., 1.));
+        assert!(hi0 < hi1);
+        assert!(hi1 < hi2);
+        assert!(hi2 < hi3);
+        assert!(hi3 < hi4);
+    }
 }
@@ -1,21 +1,41 @@
 #![feature(total_cmp)]
 #![feature(slice_partition_at_index)]
 mod coordinate;
+mod curve;
 pub mod from_wkt;
 mod hilbert;
+mod morton;
 mod packed_rtree;
 mod packed_rtree_auto_simd;
+mod packed_rtree_compact;
+mod packed_rtree_lsm;
+mod packed_rtree_map;
+mod packed_rtree_simd;
+mod packed_rtree_soa;
 mod packed_rtree_unsorted;
 mod rectangle;
+mod rstar_tree;
 mod rtree;
 pub mod utils;
 
 pub use coordinate::Coordinate;
-pub use packed_rtree::PackedRTree;
+pub use curve::SpaceFillingCurve;
+pub use hilbert::{Hilbert, Hilbert64};
+pub use morton::Morton;
+pub use packed_rtree::{FromRectangles, PackedRTree, PackedRTreeSortedAutoSimd, SortedPackedRTree};
 pub use packed_rtree_auto_simd::PackedRTreeAutoSimd;
-pub use packed_rtree_unsorted::PackedRTreeUnsorted;
+pub use packed_rtree_compact::{
+    CompactEnvelope, F32Envelope, PackedRTreeCompact, PackedRTreeF32, PackedRTreeQuantized,
+    QuantizedEnvelope,
+};
+pub use packed_rtree_lsm::PackedRTreeLsm;
+pub use packed_rtree_map::PackedRTreeMap;
+pub use packed_rtree_simd::{PackedRTreeSimd, SimdKernel};
+pub use packed_rtree_soa::PackedRTreeSoa;
+pub use packed_rtree_unsorted::{PackedRTreeUnsorted, QueryResult, RefitReport};
 pub use rectangle::{HasEnvelope, Rectangle};
-pub use rtree::RTree;
+pub use rstar_tree::RStarTree;
+pub use rtree::{BatchResults, DynQuery, QueryIter, QueryScratch, RTree};
 
 #[cfg(test)]
 mod tests;
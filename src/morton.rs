@@ -0,0 +1,119 @@
+use crate::{Coordinate, Rectangle, SpaceFillingCurve};
+
+/**
+ * Z-order (Morton) curve over a rectangle, with 16 bits per axis.
+ *
+ * This is cheaper to compute than Hilbert, but has worse locality.
+ */
+pub struct Morton {
+    x_min: f64,
+    y_min: f64,
+    x_scale: f64,
+    y_scale: f64,
+}
+
+impl Morton {
+    #[allow(clippy::float_cmp)]
+    pub fn new(env: Rectangle) -> Self {
+        let morton_max = ((1 << 16) - 1) as f64;
+        let (x_min, x_scale) = if env.is_empty() || env.x_min == env.x_max {
+            (0., 0.)
+        } else {
+            (env.x_min, morton_max / (env.x_max - env.x_min))
+        };
+        let (y_min, y_scale) = if env.is_empty() || env.y_min == env.y_max {
+            (0., 0.)
+        } else {
+            (env.y_min, morton_max / (env.y_max - env.y_min))
+        };
+        Morton {
+            x_min,
+            y_min,
+            x_scale,
+            y_scale,
+        }
+    }
+
+    /**
+     * Returns the morton index of position in the rectangle.
+     *
+     * Like Hilbert::hilbert, this does not check bounds.
+     */
+    pub fn morton(&self, position: Coordinate) -> u32 {
+        let x = self.x_scale * (position.x - self.x_min);
+        let y = self.y_scale * (position.y - self.y_min);
+        Self::morton_normalized(x.floor() as u32, y.floor() as u32)
+    }
+
+    /// Interleave the lower 16 bits of x and y, with x in the even bits.
+    pub fn morton_normalized(x: u32, y: u32) -> u32 {
+        (spread(y) << 1) | spread(x)
+    }
+}
+
+impl SpaceFillingCurve for Morton {
+    type Key = u32;
+
+    fn from_envelope(envelope: Rectangle) -> Self {
+        Morton::new(envelope)
+    }
+
+    fn key(&self, position: Coordinate) -> u32 {
+        self.morton(position)
+    }
+}
+
+/// Spread the lower 16 bits of x into the even bits of the result.
+fn spread(mut x: u32) -> u32 {
+    x &= 0xFFFF;
+    x = (x | (x << 8)) & 0x00FF_00FF;
+    x = (x | (x << 4)) & 0x0F0F_0F0F;
+    x = (x | (x << 2)) & 0x3333_3333;
+    x = (x | (x << 1)) & 0x5555_5555;
+    x
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalized() {
+        assert_eq!(Morton::morton_normalized(0, 0), 0);
+        assert_eq!(Morton::morton_normalized(1, 0), 1);
+        assert_eq!(Morton::morton_normalized(0, 1), 2);
+        assert_eq!(Morton::morton_normalized(1, 1), 3);
+        assert_eq!(Morton::morton_normalized(2, 0), 4);
+        assert_eq!(Morton::morton_normalized(0xFFFF, 0xFFFF), u32::MAX);
+    }
+
+    #[test]
+    fn morton_from_position() {
+        let total_rect = Rectangle::new((1., 2.).into(), (2., 8.).into());
+        let position = Coordinate::new(1.25, 5.);
+        let m = Morton::new(total_rect);
+        // x = floor(0.25 * 65535) y = floor(0.5 * 65535)
+        assert_eq!(m.morton(position), Morton::morton_normalized(16383, 32767));
+    }
+
+    #[test]
+    fn morton_with_degenerate_rect() {
+        let position = Coordinate::new(1., 1.);
+        let total_rect = Rectangle::new(position, position);
+        let m = Morton::new(total_rect);
+        assert_eq!(m.morton(position), 0);
+    }
+
+    #[test]
+    fn morton_ordering() {
+        let total_rect = Rectangle::new((0., 0.).into(), (4., 4.).into());
+        let m = Morton::new(total_rect);
+        let mo0 = m.morton(Coordinate::new(1., 1.));
+        let mo1 = m.morton(Coordinate::new(3., 1.));
+        let mo2 = m.morton(Coordinate::new(1., 3.));
+        let mo3 = m.morton(Coordinate::new(3., 3.));
+        assert!(mo0 < mo1);
+        assert!(mo1 < mo2);
+        assert!(mo2 < mo3);
+    }
+}
@@ -1,17 +1,62 @@
 use std::cmp::Ordering;
+use std::ops::ControlFlow;
 
-use crate::hilbert::Hilbert;
-use crate::utils::divup;
-use crate::{HasEnvelope, PackedRTreeUnsorted, RTree, Rectangle};
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+
+use crate::utils::{divup, total_cmp};
+use crate::{
+    Coordinate, HasEnvelope, Hilbert, Hilbert64, Morton, PackedRTreeAutoSimd, PackedRTreeUnsorted,
+    QueryScratch, RTree, Rectangle, RefitReport, SpaceFillingCurve,
+};
 
 type Entry = (usize, Rectangle);
 
-pub struct PackedRTree {
-    raw_rtree: PackedRTreeUnsorted,
+/// A static Rtree that can be built from rectangles in leaf order.
+pub trait FromRectangles: RTree {
+    fn new_empty() -> Self;
+    fn from_rectangles(degree: usize, rects: Vec<Rectangle>) -> Self;
+}
+
+impl FromRectangles for PackedRTreeUnsorted {
+    fn new_empty() -> Self {
+        PackedRTreeUnsorted::new_empty()
+    }
+
+    fn from_rectangles(degree: usize, rects: Vec<Rectangle>) -> Self {
+        PackedRTreeUnsorted::new(degree, rects)
+    }
+}
+
+impl FromRectangles for PackedRTreeAutoSimd {
+    fn new_empty() -> Self {
+        PackedRTreeAutoSimd::new_empty()
+    }
+
+    fn from_rectangles(degree: usize, rects: Vec<Rectangle>) -> Self {
+        PackedRTreeAutoSimd::new(degree, &rects)
+    }
+}
+
+/**
+ * A static Rtree whose leaves are sorted for spatial locality.
+ *
+ * The leaves are stored in the raw tree in sorted order, and results are
+ * mapped back to the indices of the original items.
+ */
+pub struct SortedPackedRTree<T> {
+    raw_rtree: T,
     shuffled_indices: Vec<usize>,
+    // The inverse of shuffled_indices, built on first use.
+    leaf_offsets: Vec<usize>,
 }
 
-impl RTree for PackedRTree {
+pub type PackedRTree = SortedPackedRTree<PackedRTreeUnsorted>;
+
+/// A sorted tree with the SIMD-friendly BBox leaf layout.
+pub type PackedRTreeSortedAutoSimd = SortedPackedRTree<PackedRTreeAutoSimd>;
+
+impl<T: RTree> RTree for SortedPackedRTree<T> {
     fn is_empty(&self) -> bool {
         self.raw_rtree.is_empty()
     }
@@ -28,85 +73,240 @@ impl RTree for PackedRTree {
         self.raw_rtree.envelope()
     }
 
-    fn query_rect(&self, query: &Rectangle) -> Vec<usize> {
-        let raw_results = self.raw_rtree.query_rect(query);
+    fn query_rect_with_scratch<F>(
+        &self,
+        query: &Rectangle,
+        scratch: &mut QueryScratch,
+        mut visitor: F,
+    ) -> ControlFlow<()>
+    where
+        F: FnMut(usize) -> ControlFlow<()>,
+    {
+        self.raw_rtree
+            .query_rect_with_scratch(query, scratch, |i| visitor(self.shuffled_indices[i]))
+    }
+
+    fn query_point_with_scratch<F>(
+        &self,
+        point: Coordinate,
+        scratch: &mut QueryScratch,
+        mut visitor: F,
+    ) -> ControlFlow<()>
+    where
+        F: FnMut(usize) -> ControlFlow<()>,
+    {
+        self.raw_rtree
+            .query_point_with_scratch(point, scratch, |i| visitor(self.shuffled_indices[i]))
+    }
+
+    fn query_self_intersections(&self) -> Vec<(usize, usize)> {
+        let raw_results = self.raw_rtree.query_self_intersections();
         raw_results
             .into_iter()
-            .map(|i| self.shuffled_indices[i])
+            .map(|(i, j)| {
+                let (i, j) = (self.shuffled_indices[i], self.shuffled_indices[j]);
+                (i.min(j), i.max(j))
+            })
             .collect()
     }
+
+    fn get_rectangle(&self, level: usize, offset: usize) -> Rectangle {
+        self.raw_rtree.get_rectangle(level, offset)
+    }
+
+    fn get_index(&self, offset: usize) -> usize {
+        self.shuffled_indices[offset]
+    }
 }
 
-impl PackedRTree {
+impl<T: FromRectangles> SortedPackedRTree<T> {
     pub fn new_empty() -> Self {
-        PackedRTree {
-            raw_rtree: PackedRTreeUnsorted::new_empty(),
+        SortedPackedRTree {
+            raw_rtree: T::new_empty(),
             shuffled_indices: Vec::new(),
+            leaf_offsets: Vec::new(),
         }
     }
 
+    /**
+     * Build a tree sorted along a Hilbert curve with 16 bits per axis.
+     *
+     * The total envelope is divided into 2^16 cells per axis, and centers in
+     * the same cell keep their input order.  If the data needs a finer grid,
+     * e.g. billions of items or small clusters in a large extent, use
+     * new_hilbert64, which has 2^32 cells per axis and sorts somewhat slower.
+     */
     pub fn new_hilbert(degree: usize, items: &[impl HasEnvelope]) -> Self {
+        Self::new_sorted::<Hilbert>(degree, items)
+    }
+
+    /// Like new_hilbert, but with 32 bits per axis, for large dense datasets.
+    pub fn new_hilbert64(degree: usize, items: &[impl HasEnvelope]) -> Self {
+        Self::new_sorted::<Hilbert64>(degree, items)
+    }
+
+    /// Build a tree sorted along a Z-order curve, which is faster to build than Hilbert.
+    pub fn new_morton(degree: usize, items: &[impl HasEnvelope]) -> Self {
+        Self::new_sorted::<Morton>(degree, items)
+    }
+
+    /// Build a tree with the leaves sorted by the curve key of their centers.
+    pub fn new_sorted<C: SpaceFillingCurve>(degree: usize, items: &[impl HasEnvelope]) -> Self {
         let total_envelope = Rectangle::of(items);
         if total_envelope.is_empty() {
             return Self::new_empty();
         }
 
-        let hilbert_square = Hilbert::new(total_envelope);
-        let mut entries: Vec<(u32, usize, Rectangle)> = items
-            .iter()
-            .map(|i| i.envelope())
+        let curve = C::from_envelope(total_envelope);
+        let envelopes: Vec<Rectangle> = items.iter().map(|i| i.envelope()).collect();
+        #[cfg(feature = "parallel")]
+        let envelopes_iter = envelopes.par_iter();
+        #[cfg(not(feature = "parallel"))]
+        let envelopes_iter = envelopes.iter();
+        let mut entries: Vec<(C::Key, usize, Rectangle)> = envelopes_iter
             .enumerate()
-            .map(|(i, e)| (hilbert_square.hilbert(e.center()), i, e))
+            .map(|(i, e)| (curve.key(e.center()), i, *e))
             .collect();
 
-        entries.sort_unstable_by_key(|&(h, _, _)| h);
+        // Break ties by index, so the parallel and serial builds agree.
+        #[cfg(feature = "parallel")]
+        entries.par_sort_unstable_by_key(|&(h, i, _)| (h, i));
+        #[cfg(not(feature = "parallel"))]
+        entries.sort_unstable_by_key(|&(h, i, _)| (h, i));
         let rects: Vec<Rectangle> = entries.iter().map(|(_h, _i, rect)| *rect).collect();
-        PackedRTree {
+        SortedPackedRTree {
             shuffled_indices: entries.iter().map(|(_h, i, _e)| *i).collect(),
-            raw_rtree: PackedRTreeUnsorted::new(degree, rects),
+            raw_rtree: T::from_rectangles(degree, rects),
+            leaf_offsets: Vec::new(),
+        }
+    }
+
+    pub(crate) fn raw_rtree(&self) -> &T {
+        &self.raw_rtree
+    }
+
+    /// The original index of each leaf.
+    pub(crate) fn shuffled_indices(&self) -> &[usize] {
+        &self.shuffled_indices
+    }
+
+    /// Build a tree with the leaves in the order of entries.
+    fn from_entries(degree: usize, entries: &[Entry]) -> Self {
+        SortedPackedRTree {
+            shuffled_indices: entries.iter().map(|(i, _e)| *i).collect(),
+            raw_rtree: T::from_rectangles(degree, entries.iter().map(|(_i, e)| *e).collect()),
+            leaf_offsets: Vec::new(),
         }
     }
 
-    pub fn new_omt(items: &[impl HasEnvelope]) -> Self {
+    /**
+     * Build a tree with Sort-Tile-Recursive packing.
+     *
+     * The items are split into vertical slices by x center, and each slice
+     * is split into leaves of degree items by y center.
+     */
+    pub fn new_str(degree: usize, items: &[impl HasEnvelope]) -> Self {
         if items.is_empty() {
             return Self::new_empty();
         }
 
-        // TODO: Calculate ncols, nrows dynamically to improve packing
-        let ncols = 4;
-        let nrows = 4;
-        let degree = ncols * nrows;
+        let degree = degree.max(2);
+        let mut entries: Vec<Entry> = items
+            .iter()
+            .map(|item| item.envelope())
+            .enumerate()
+            .collect();
+
+        let num_leaves = divup(entries.len(), degree);
+        let num_slices = (num_leaves as f64).sqrt().ceil() as usize;
+        // Keep slices a multiple of degree, so leaves don't straddle slices.
+        let slice_size = degree * divup(num_leaves, num_slices);
+        partition_to_chunks(slice_size, &mut entries, true);
+        #[cfg(feature = "parallel")]
+        let slices = entries.par_chunks_mut(slice_size);
+        #[cfg(not(feature = "parallel"))]
+        let slices = entries.chunks_mut(slice_size);
+        slices.for_each(|slice| partition_to_chunks(degree, slice, false));
+
+        Self::from_entries(degree, &entries)
+    }
+
+    /**
+     * Build a tree with Overlap Minimizing Top-down packing.
+     *
+     * Each level is split into vertical slabs by x center, then each slab
+     * into horizontal slabs by y center, so that each child is a full
+     * subtree.  The number of slabs depends on degree and the number of items.
+     */
+    pub fn new_omt(degree: usize, items: &[impl HasEnvelope]) -> Self {
+        if items.is_empty() {
+            return Self::new_empty();
+        }
+
+        let degree = degree.max(2);
+        let mut height = 1;
+        while degree.pow(height) < items.len() {
+            height += 1;
+        }
 
         let mut entries: Vec<Entry> = items
             .iter()
             .map(|item| item.envelope())
             .enumerate()
             .collect();
-        let mut offsets = vec![0];
-        offsets.extend(partition_omt(&mut entries, ncols, nrows, 0));
-        offsets.sort_unstable();
-
-        let total_size = divup(items.len(), degree) * degree;
-        let mut shuffled_indices: Vec<usize> = Vec::with_capacity(total_size);
-        let mut rects: Vec<Rectangle> = Vec::with_capacity(total_size);
-        // If we match an empty rect, this will cause an out-of-bounds panic.
-        let empty_index = usize::MAX;
-        let empty_rect = Rectangle::new_empty();
-        for (&last_offset, &next_offset) in offsets.iter().zip(&offsets[1..]) {
-            let these_entries = &entries[last_offset..next_offset];
-            shuffled_indices.extend(these_entries.iter().map(|(i, _e)| i));
-            rects.extend(these_entries.iter().map(|(_i, e)| e));
-            // Pad leaves so that we always have a multiple of degree
-            let excess = degree - (next_offset - last_offset);
-            shuffled_indices.extend(vec![empty_index; excess]);
-            rects.extend(vec![empty_rect; excess]);
+        partition_omt(&mut entries, degree, height);
+
+        Self::from_entries(degree, &entries)
+    }
+}
+
+impl<T> SortedPackedRTree<T> {
+    /// The leaf offset of the item with index, if it is in the tree.
+    fn leaf_offset(&mut self, index: usize) -> Option<usize> {
+        if self.leaf_offsets.is_empty() && !self.shuffled_indices.is_empty() {
+            let max_index = self.shuffled_indices.iter().max().unwrap();
+            self.leaf_offsets = vec![usize::MAX; max_index + 1];
+            for (offset, &i) in self.shuffled_indices.iter().enumerate() {
+                self.leaf_offsets[i] = offset;
+            }
         }
+        self.leaf_offsets
+            .get(index)
+            .copied()
+            .filter(|&offset| offset != usize::MAX)
+    }
+}
 
-        PackedRTree {
-            shuffled_indices,
-            raw_rtree: PackedRTreeUnsorted::new(degree, rects),
+impl PackedRTree {
+    /**
+     * Remove the item with index, returning whether it was present.
+     *
+     * Ancestor envelopes are not shrunk until refit is called.
+     */
+    pub fn remove(&mut self, index: usize) -> bool {
+        match self.leaf_offset(index) {
+            Some(offset) => self.raw_rtree.remove(offset),
+            None => false,
         }
     }
+
+    /**
+     * Change the envelope of the item with index, keeping its place in the
+     * tree, returning whether the tree has an item with index.
+     *
+     * Ancestor envelopes are expanded, but not shrunk until refit is called.
+     */
+    pub fn update_envelope(&mut self, index: usize, envelope: Rectangle) -> bool {
+        match self.leaf_offset(index) {
+            Some(offset) => self.raw_rtree.update_envelope(offset, envelope),
+            None => false,
+        }
+    }
+
+    /// Recompute the envelopes of all nodes from their children, bottom-up.
+    pub fn refit(&mut self) -> RefitReport {
+        self.raw_rtree.refit()
+    }
 }
 
 fn x_center(rect: &Rectangle) -> f64 {
@@ -127,51 +327,36 @@ fn cmp_y(entry1: &Entry, entry2: &Entry) -> Ordering {
     total_cmp(y_center(&entry1.1), y_center(&entry2.1))
 }
 
-fn total_cmp(x1: f64, x2: f64) -> Ordering {
-    // TODO: Use total_cmp when stabilized
-    // x1.total_cmp(&x2)
-    match x1.partial_cmp(&x2) {
-        Some(ord) => ord,
-        None => {
-            // One or both is a NaN
-            if x1.is_nan() {
-                if x2.is_nan() {
-                    Ordering::Equal
-                } else {
-                    Ordering::Greater
-                }
-            } else {
-                Ordering::Less
-            }
-        }
-    }
-}
-
-fn partition_omt(entries: &mut [Entry], ncols: usize, nrows: usize, start: usize) -> Vec<usize> {
+/**
+ * Partition entries into subtrees of the given height.
+ *
+ * Every child subtree is full except for the last, so every leaf except the
+ * last has exactly degree items, and the leaves need no padding.
+ */
+fn partition_omt(entries: &mut [Entry], degree: usize, height: u32) {
     let size = entries.len();
-    if size < ncols * nrows {
-        return vec![start + size];
+    if height <= 1 || size <= degree {
+        return;
     }
 
-    let mut results = Vec::new();
-    let column_size = divup(size, ncols);
-    partition_to_chunks(column_size, entries, true);
-    for ix in (0..size).step_by(column_size) {
-        let actual_column_size = size.min(ix + column_size) - ix;
-        let row_size = divup(actual_column_size, nrows);
-        partition_to_chunks(row_size, &mut entries[ix..(ix + actual_column_size)], false);
-        for iy in (ix..(ix + actual_column_size)).step_by(row_size) {
-            let actual_row_size = (ix + actual_column_size).min(iy + row_size) - iy;
-            results.extend(partition_omt(
-                &mut entries[iy..(iy + actual_row_size)],
-                ncols,
-                nrows,
-                iy,
-            ))
-        }
-    }
+    let child_capacity = degree.pow(height - 1);
+    let num_children = divup(size, child_capacity);
+    let ncols = (num_children as f64).sqrt().ceil() as usize;
+    let column_size = child_capacity * divup(num_children, ncols);
 
-    results
+    partition_to_chunks(column_size, entries, true);
+    #[cfg(feature = "parallel")]
+    let columns = entries.par_chunks_mut(column_size);
+    #[cfg(not(feature = "parallel"))]
+    let columns = entries.chunks_mut(column_size);
+    columns.for_each(|column| {
+        partition_to_chunks(child_capacity, column, false);
+        #[cfg(feature = "parallel")]
+        let children = column.par_chunks_mut(child_capacity);
+        #[cfg(not(feature = "parallel"))]
+        let children = column.chunks_mut(child_capacity);
+        children.for_each(|child| partition_omt(child, degree, height - 1));
+    });
 }
 
 // Ported from github.com/mourner/rbush
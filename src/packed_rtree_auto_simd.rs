@@ -1,5 +1,6 @@
 use crate::utils::{calculate_level_indices, copy_into_slice};
-use crate::{Coordinate, RTree, Rectangle};
+use crate::{HasEnvelope, QueryScratch, RTree, Rectangle};
+use core::ops::ControlFlow;
 
 // [x_min, y_min, -xmax, -ymax]
 #[repr(align(64))]
@@ -16,6 +17,10 @@ impl BBox {
             y_max: -self.0[3],
         }
     }
+
+    fn is_empty(&self) -> bool {
+        self.0[0] > -self.0[2] || self.0[1] > -self.0[3]
+    }
 }
 
 impl From<&Rectangle> for BBox {
@@ -50,50 +55,40 @@ impl RTree for PackedRTreeAutoSimd {
         }
     }
 
-    fn query_rect(&self, rect: &Rectangle) -> Vec<usize> {
-        let mut results = Vec::new();
-        if self.is_empty() {
-            return results;
-        }
-
+    fn query_rect_with_scratch<F>(
+        &self,
+        rect: &Rectangle,
+        scratch: &mut QueryScratch,
+        visitor: F,
+    ) -> ControlFlow<()>
+    where
+        F: FnMut(usize) -> ControlFlow<()>,
+    {
         // Rearrange this for fast checking
         let query_bbox = BBox([rect.x_max, rect.y_max, -rect.x_min, -rect.y_min]);
+        self.visit(
+            |tree_bbox| {
+                (tree_bbox.0[0] <= query_bbox.0[0])
+                    & (tree_bbox.0[1] <= query_bbox.0[1])
+                    & (tree_bbox.0[2] <= query_bbox.0[2])
+                    & (tree_bbox.0[3] <= query_bbox.0[3])
+            },
+            scratch,
+            visitor,
+        )
+    }
 
-        // Stack entries: (level, offset)
-        let mut stack = Vec::new();
-        {
-            let root = self.root();
-            let root_bbox = self.get_bbox(root.0, root.1);
-            if (root_bbox.0[0] <= query_bbox.0[0])
-                & (root_bbox.0[1] <= query_bbox.0[1])
-                & (root_bbox.0[2] <= query_bbox.0[2])
-                & (root_bbox.0[3] <= query_bbox.0[3])
-            {
-                stack.push(root);
-            }
-        }
-
-        while let Some((level, offset)) = stack.pop() {
-            if level == 0 {
-                results.push(offset);
-            } else {
-                let child_level = level - 1;
-                let first_child_offset = self.degree * offset;
-                let first_child_index = self.find_index(child_level, first_child_offset);
-                let children = &self.tree[first_child_index..(first_child_index + self.degree)];
-                children.iter().enumerate().for_each(|(inc, tree_bbox)| {
-                    if (tree_bbox.0[0] <= query_bbox.0[0])
-                        & (tree_bbox.0[1] <= query_bbox.0[1])
-                        & (tree_bbox.0[2] <= query_bbox.0[2])
-                        & (tree_bbox.0[3] <= query_bbox.0[3])
-                    {
-                        stack.push((child_level, first_child_offset + inc))
-                    }
-                });
-            }
+    fn get_rectangle(&self, level: usize, offset: usize) -> Rectangle {
+        let bbox = self.get_bbox(level, offset);
+        if bbox.is_empty() {
+            Rectangle::new_empty()
+        } else {
+            bbox.to_rectangle()
         }
+    }
 
-        results
+    fn get_index(&self, offset: usize) -> usize {
+        offset
     }
 }
 
@@ -112,18 +107,18 @@ impl PackedRTreeAutoSimd {
         }
     }
 
-    pub fn new(mut degree: usize, rects: &[Rectangle]) -> Self {
-        if rects.is_empty() {
+    pub fn new(mut degree: usize, items: &[impl HasEnvelope]) -> Self {
+        if items.is_empty() {
             return PackedRTreeAutoSimd::new_empty();
         }
 
         degree = degree.max(2);
-        let size = rects.len();
+        let size = items.len();
         let level_indices = calculate_level_indices(degree, size);
         let tree_size = level_indices[level_indices.len() - 1] + 1;
         let mut tree = vec![BBox::EMPTY_BBOX; tree_size];
-        for (i, rect) in rects.iter().enumerate() {
-            tree[i] = rect.into();
+        for (i, item) in items.iter().enumerate() {
+            tree[i] = (&item.envelope()).into();
         }
 
         for level in 1..level_indices.len() {
@@ -154,8 +149,41 @@ impl PackedRTreeAutoSimd {
         }
     }
 
-    pub fn query_point(&self, coord: Coordinate) -> Vec<usize> {
-        self.query_rect(&Rectangle::new(coord, coord))
+    /// Visit the leaves whose bbox, and every ancestor's bbox, matches.
+    fn visit<F, M>(&self, matches: M, scratch: &mut QueryScratch, mut visitor: F) -> ControlFlow<()>
+    where
+        F: FnMut(usize) -> ControlFlow<()>,
+        M: Fn(&BBox) -> bool,
+    {
+        if self.is_empty() {
+            return ControlFlow::Continue(());
+        }
+
+        // Stack entries: (level, offset)
+        let stack = &mut scratch.stack;
+        stack.clear();
+        let root = self.root();
+        if matches(&self.get_bbox(root.0, root.1)) {
+            stack.push(root);
+        }
+
+        while let Some((level, offset)) = stack.pop() {
+            if level == 0 {
+                visitor(offset)?;
+            } else {
+                let child_level = level - 1;
+                let first_child_offset = self.degree * offset;
+                let first_child_index = self.find_index(child_level, first_child_offset);
+                let children = &self.tree[first_child_index..(first_child_index + self.degree)];
+                children.iter().enumerate().for_each(|(inc, tree_bbox)| {
+                    if matches(tree_bbox) {
+                        stack.push((child_level, first_child_offset + inc))
+                    }
+                });
+            }
+        }
+
+        ControlFlow::Continue(())
     }
 
     fn find_index(&self, level: usize, offset: usize) -> usize {
@@ -0,0 +1,411 @@
+/**
+ * A static Rtree that stores internal node envelopes in compact form.
+ *
+ * Internal nodes are encoded conservatively, so that each decoded envelope
+ * contains the exact one, and leaves are kept as exact f64 rectangles.  This
+ * means queries may descend into a few extra nodes, but the results are the
+ * same as for PackedRTreeUnsorted.
+ */
+use crate::utils::calculate_level_indices;
+use crate::{QueryScratch, RTree, Rectangle};
+use core::ops::ControlFlow;
+
+/**
+ * A compact encoding of a node envelope, possibly relative to its parent.
+ *
+ * The decoded rectangle must contain the encoded one, and empty rectangles
+ * must decode to empty rectangles.
+ */
+pub trait CompactEnvelope: Copy {
+    fn encode(rect: &Rectangle, parent: &Rectangle) -> Self;
+    fn decode(&self, parent: &Rectangle) -> Rectangle;
+}
+
+/// Envelope coordinates as f32, with mins rounded down and maxes rounded up.
+#[derive(Copy, Clone, Debug)]
+pub struct F32Envelope([f32; 4]);
+
+impl CompactEnvelope for F32Envelope {
+    fn encode(rect: &Rectangle, _parent: &Rectangle) -> Self {
+        F32Envelope([
+            f32_down(rect.x_min),
+            f32_down(rect.y_min),
+            f32_up(rect.x_max),
+            f32_up(rect.y_max),
+        ])
+    }
+
+    fn decode(&self, _parent: &Rectangle) -> Rectangle {
+        Rectangle {
+            x_min: self.0[0] as f64,
+            y_min: self.0[1] as f64,
+            x_max: self.0[2] as f64,
+            y_max: self.0[3] as f64,
+        }
+    }
+}
+
+/// The largest f32 <= x.
+fn f32_down(x: f64) -> f32 {
+    let y = x as f32;
+    if (y as f64) <= x || y.is_nan() {
+        y
+    } else if y > 0. {
+        f32::from_bits(y.to_bits() - 1)
+    } else if y == 0. {
+        -f32::from_bits(1)
+    } else {
+        f32::from_bits(y.to_bits() + 1)
+    }
+}
+
+/// The smallest f32 >= x.
+fn f32_up(x: f64) -> f32 {
+    -f32_down(-x)
+}
+
+/**
+ * Envelope coordinates as 16-bit fractions of the parent envelope, with mins
+ * rounded down and maxes rounded up.
+ */
+#[derive(Copy, Clone, Debug)]
+pub struct QuantizedEnvelope([u16; 4]);
+
+impl QuantizedEnvelope {
+    const SCALE: f64 = u16::MAX as f64;
+    // Inverted, so it can't be mistaken for a real envelope.
+    const EMPTY: QuantizedEnvelope = QuantizedEnvelope([u16::MAX, u16::MAX, 0, 0]);
+
+    fn decode_coord(q: u16, min: f64, max: f64) -> f64 {
+        if q == u16::MAX {
+            // Avoid rounding past the parent.
+            max
+        } else {
+            min + (max - min) * (q as f64 / Self::SCALE)
+        }
+    }
+
+    fn encode_min(x: f64, min: f64, max: f64) -> u16 {
+        let width = max - min;
+        if width <= 0. {
+            return 0;
+        }
+        let mut q = (((x - min) / width) * Self::SCALE)
+            .floor()
+            .clamp(0., Self::SCALE) as u16;
+        while q > 0 && Self::decode_coord(q, min, max) > x {
+            q -= 1;
+        }
+        q
+    }
+
+    fn encode_max(x: f64, min: f64, max: f64) -> u16 {
+        let width = max - min;
+        if width <= 0. {
+            return u16::MAX;
+        }
+        let mut q = (((x - min) / width) * Self::SCALE)
+            .ceil()
+            .clamp(0., Self::SCALE) as u16;
+        while q < u16::MAX && Self::decode_coord(q, min, max) < x {
+            q += 1;
+        }
+        q
+    }
+}
+
+impl CompactEnvelope for QuantizedEnvelope {
+    fn encode(rect: &Rectangle, parent: &Rectangle) -> Self {
+        if rect.is_empty() || parent.is_empty() {
+            return Self::EMPTY;
+        }
+        QuantizedEnvelope([
+            Self::encode_min(rect.x_min, parent.x_min, parent.x_max),
+            Self::encode_min(rect.y_min, parent.y_min, parent.y_max),
+            Self::encode_max(rect.x_max, parent.x_min, parent.x_max),
+            Self::encode_max(rect.y_max, parent.y_min, parent.y_max),
+        ])
+    }
+
+    fn decode(&self, parent: &Rectangle) -> Rectangle {
+        if self.0[0] > self.0[2] || self.0[1] > self.0[3] || parent.is_empty() {
+            return Rectangle::new_empty();
+        }
+        Rectangle {
+            x_min: Self::decode_coord(self.0[0], parent.x_min, parent.x_max),
+            y_min: Self::decode_coord(self.0[1], parent.y_min, parent.y_max),
+            x_max: Self::decode_coord(self.0[2], parent.x_min, parent.x_max),
+            y_max: Self::decode_coord(self.0[3], parent.y_min, parent.y_max),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PackedRTreeCompact<E: CompactEnvelope> {
+    degree: usize,
+    size: usize,
+    // nodes in level i are (level_indices[i] .. level_indices[i + 1]) (end exclusive)
+    level_indices: Vec<usize>,
+    envelope: Rectangle,
+    leaves: Vec<Rectangle>,
+    // Levels 1 and up, each encoded relative to its decoded parent.  The root
+    // is encoded relative to the envelope.
+    nodes: Vec<E>,
+}
+
+/// Internal nodes stored as f32, halving their memory.
+pub type PackedRTreeF32 = PackedRTreeCompact<F32Envelope>;
+
+/// Internal nodes stored as u16 offsets in their parents, quartering their memory.
+pub type PackedRTreeQuantized = PackedRTreeCompact<QuantizedEnvelope>;
+
+impl<E: CompactEnvelope> RTree for PackedRTreeCompact<E> {
+    fn is_empty(&self) -> bool {
+        self.size == 0
+    }
+
+    fn height(&self) -> usize {
+        self.level_indices.len()
+    }
+
+    fn degree(&self) -> usize {
+        self.degree
+    }
+
+    fn envelope(&self) -> Rectangle {
+        self.envelope
+    }
+
+    fn query_rect_with_scratch<F>(
+        &self,
+        query: &Rectangle,
+        scratch: &mut QueryScratch,
+        visitor: F,
+    ) -> ControlFlow<()>
+    where
+        F: FnMut(usize) -> ControlFlow<()>,
+    {
+        self.visit(|envelope| query.intersects(envelope), scratch, visitor)
+    }
+
+    /// Like the default, but decodes each child once from its parent's envelope.
+    fn query_self_intersections(&self) -> Vec<(usize, usize)> {
+        let mut results = Vec::new();
+        if self.is_empty() || self.height() == 1 {
+            return results;
+        }
+
+        // Stack entries: (level, offset1, offset2, envelope1, envelope2),
+        // with offset1 <= offset2 and intersecting decoded envelopes.
+        let root = self.decoded_root();
+        let mut stack = vec![(self.height() - 1, 0, 0, root, root)];
+
+        while let Some((level, offset1, offset2, parent1, parent2)) = stack.pop() {
+            let child_level = level - 1;
+            let first_child_offset1 = self.degree * offset1;
+            let first_child_offset2 = self.degree * offset2;
+            for inc1 in 0..self.degree {
+                let child_offset1 = first_child_offset1 + inc1;
+                let child1 = self.decode_child(child_level, child_offset1, &parent1);
+                let start2 = if offset1 == offset2 { inc1 } else { 0 };
+                for inc2 in start2..self.degree {
+                    let child_offset2 = first_child_offset2 + inc2;
+                    let child2 = self.decode_child(child_level, child_offset2, &parent2);
+                    if !child1.intersects(&child2) {
+                        continue;
+                    }
+                    if child_level == 0 {
+                        if child_offset1 != child_offset2 {
+                            results.push((child_offset1, child_offset2));
+                        }
+                    } else {
+                        stack.push((child_level, child_offset1, child_offset2, child1, child2));
+                    }
+                }
+            }
+        }
+
+        results
+    }
+
+    fn get_rectangle(&self, level: usize, offset: usize) -> Rectangle {
+        if level == 0 {
+            self.leaves[offset]
+        } else if level == self.height() - 1 {
+            self.decoded_root()
+        } else {
+            let parent = self.get_rectangle(level + 1, offset / self.degree);
+            self.node(level, offset).decode(&parent)
+        }
+    }
+
+    fn get_index(&self, offset: usize) -> usize {
+        offset
+    }
+}
+
+impl<E: CompactEnvelope> PackedRTreeCompact<E> {
+    pub fn new_empty() -> Self {
+        Self {
+            degree: 2,
+            size: 0,
+            level_indices: Vec::new(),
+            envelope: Rectangle::new_empty(),
+            leaves: Vec::new(),
+            nodes: Vec::new(),
+        }
+    }
+
+    pub fn new(mut degree: usize, mut rects: Vec<Rectangle>) -> Self {
+        if rects.is_empty() {
+            return Self::new_empty();
+        }
+
+        degree = degree.max(2);
+        let size = rects.len();
+        let level_indices = calculate_level_indices(degree, size);
+        let height = level_indices.len();
+        let num_leaves = if height == 1 { 1 } else { level_indices[1] };
+        rects.resize(num_leaves, Rectangle::new_empty());
+
+        // Build the exact envelopes of the internal nodes, by level.
+        let mut exact_levels: Vec<Vec<Rectangle>> = Vec::with_capacity(height);
+        for level in 1..height {
+            let children: &[Rectangle] = exact_levels.last().unwrap_or(&rects);
+            let mut next: Vec<Rectangle> = children.chunks(degree).map(Rectangle::of).collect();
+            let capacity = level_indices
+                .get(level + 1)
+                .unwrap_or(&(level_indices[level] + 1))
+                - level_indices[level];
+            next.resize(capacity, Rectangle::new_empty());
+            exact_levels.push(next);
+        }
+        let envelope = Rectangle::of(&rects);
+
+        // Encode from the top down, so children are encoded relative to the
+        // envelope their parent decodes to.
+        let num_nodes = level_indices[height - 1] + 1 - num_leaves;
+        let mut nodes: Vec<E> = Vec::with_capacity(num_nodes);
+        nodes.resize(num_nodes, E::encode(&Rectangle::new_empty(), &envelope));
+        let mut parents = vec![envelope];
+        for level in (1..height).rev() {
+            let exact_level = &exact_levels[level - 1];
+            let mut decoded = Vec::with_capacity(exact_level.len());
+            for (offset, rect) in exact_level.iter().enumerate() {
+                let parent = &parents[offset / degree];
+                let node = E::encode(rect, parent);
+                decoded.push(node.decode(parent));
+                nodes[level_indices[level] - num_leaves + offset] = node;
+            }
+            parents = decoded;
+        }
+
+        rects.shrink_to_fit();
+        Self {
+            degree,
+            size,
+            level_indices,
+            envelope,
+            leaves: rects,
+            nodes,
+        }
+    }
+
+    /// Visit the leaves whose envelope, and every ancestor's decoded
+    /// envelope, matches.
+    fn visit<F, M>(&self, matches: M, scratch: &mut QueryScratch, mut visitor: F) -> ControlFlow<()>
+    where
+        F: FnMut(usize) -> ControlFlow<()>,
+        M: Fn(&Rectangle) -> bool,
+    {
+        if self.is_empty() || !matches(&self.envelope) {
+            return ControlFlow::Continue(());
+        }
+        if self.height() == 1 {
+            return visitor(0);
+        }
+
+        // Stack entries are (level, offset), with the decoded envelope of the
+        // node at the same position of envelopes.
+        let stack = &mut scratch.stack;
+        let envelopes = &mut scratch.envelopes;
+        stack.clear();
+        envelopes.clear();
+        stack.push(self.root());
+        envelopes.push(self.decoded_root());
+
+        while let (Some((level, offset)), Some(parent)) = (stack.pop(), envelopes.pop()) {
+            let child_level = level - 1;
+            let first_child_offset = self.degree * offset;
+            for child_offset in first_child_offset..(first_child_offset + self.degree) {
+                if child_level == 0 {
+                    // Leaves are exact
+                    if matches(&self.leaves[child_offset]) {
+                        visitor(child_offset)?;
+                    }
+                    continue;
+                }
+                let child = self.node(child_level, child_offset).decode(&parent);
+                if matches(&child) {
+                    stack.push((child_level, child_offset));
+                    envelopes.push(child);
+                }
+            }
+        }
+
+        ControlFlow::Continue(())
+    }
+
+    fn root(&self) -> (usize, usize) {
+        (self.height() - 1, 0)
+    }
+
+    fn node(&self, level: usize, offset: usize) -> E {
+        self.nodes[self.level_indices[level] - self.level_indices[1] + offset]
+    }
+
+    fn decoded_root(&self) -> Rectangle {
+        let (level, offset) = self.root();
+        if level == 0 {
+            self.leaves[0]
+        } else {
+            self.node(level, offset).decode(&self.envelope)
+        }
+    }
+
+    fn decode_child(&self, level: usize, offset: usize, parent: &Rectangle) -> Rectangle {
+        if level == 0 {
+            self.leaves[offset]
+        } else {
+            self.node(level, offset).decode(parent)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[allow(clippy::float_cmp)]
+    #[test]
+    fn f32_rounding_is_conservative() {
+        for &x in [0., 0.1, -0.1, 1e-40, -1e-40, 1e39, -1e39, 123_456_789.123].iter() {
+            assert!(f32_down(x) as f64 <= x);
+            assert!(f32_up(x) as f64 >= x);
+        }
+        assert_eq!(f32_down(0.5), 0.5);
+        assert_eq!(f32_up(0.5), 0.5);
+    }
+
+    #[test]
+    fn quantized_is_conservative() {
+        let parent = Rectangle::new((-3.3, 1.).into(), (7.7, 1.).into());
+        let rect = Rectangle::new((0.1, 1.).into(), (0.2, 1.).into());
+        let decoded = QuantizedEnvelope::encode(&rect, &parent).decode(&parent);
+        assert!(decoded.contains(&rect));
+        assert!(parent.contains(&decoded));
+
+        let empty = QuantizedEnvelope::encode(&Rectangle::new_empty(), &parent);
+        assert!(empty.decode(&parent).is_empty());
+    }
+}
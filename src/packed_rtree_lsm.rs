@@ -0,0 +1,417 @@
+/**
+ * An appendable index made of immutable packed Rtrees, log-structured.
+ *
+ * Each appended batch becomes a small Hilbert-packed run.  Trailing runs are
+ * merged into a single run whenever the run before them is less than
+ * MERGE_RATIO times their total size, so run sizes grow geometrically and
+ * there are O(log n) of them.  Merges run in the background, on the rayon
+ * pool with the parallel feature and on their own thread otherwise, and
+ * queries use the old runs until the merge finishes.  A panic in a merge is
+ * resumed on the appending thread when the merge is installed.
+ *
+ * Only one merge runs at a time.  If many batches are appended while a large
+ * merge is running, append waits for it once MAX_UNMERGED_RUNS runs are
+ * waiting behind it, so the number of runs stays bounded.
+ *
+ * Items get global indices in order of appending, which are stable across
+ * merges.  For the RTree trait, runs are padded to the same height and are
+ * the children of virtual nodes above them.
+ */
+use core::ops::{ControlFlow, Range};
+use std::panic;
+#[cfg(feature = "parallel")]
+use std::sync::mpsc::{channel, Receiver, TryRecvError};
+use std::sync::Arc;
+#[cfg(feature = "parallel")]
+use std::sync::Mutex;
+#[cfg(not(feature = "parallel"))]
+use std::thread::JoinHandle;
+
+use crate::{Coordinate, HasEnvelope, PackedRTree, QueryScratch, RTree, Rectangle};
+
+const DEFAULT_DEGREE: usize = 16;
+const MERGE_RATIO: usize = 2;
+const MAX_UNMERGED_RUNS: usize = 16;
+
+struct Run {
+    tree: PackedRTree,
+    // Global index of each item, by index in tree.
+    indices: Vec<usize>,
+}
+
+impl Run {
+    fn new(degree: usize, indices: Vec<usize>, envelopes: &[Rectangle]) -> Self {
+        Run {
+            tree: PackedRTree::new_hilbert(degree, envelopes),
+            indices,
+        }
+    }
+
+    /// Build a run of the items of runs, reading envelopes from their leaves.
+    fn merge(degree: usize, runs: &[Arc<Run>]) -> Self {
+        let size = runs.iter().map(|run| run.len()).sum();
+        let mut indices = Vec::with_capacity(size);
+        let mut envelopes = Vec::with_capacity(size);
+        for run in runs {
+            let leaves = run.tree.raw_rtree().leaves();
+            for (leaf, &i) in leaves.iter().zip(run.tree.shuffled_indices()) {
+                indices.push(run.indices[i]);
+                envelopes.push(*leaf);
+            }
+        }
+        Run::new(degree, indices, &envelopes)
+    }
+
+    fn len(&self) -> usize {
+        self.indices.len()
+    }
+}
+
+struct PendingMerge {
+    runs: Range<usize>,
+    handle: MergeHandle,
+}
+
+/// The result of a merge running in the background.
+#[cfg(feature = "parallel")]
+struct MergeHandle(Mutex<Receiver<std::thread::Result<Run>>>);
+
+#[cfg(not(feature = "parallel"))]
+struct MergeHandle(JoinHandle<Run>);
+
+impl MergeHandle {
+    #[cfg(feature = "parallel")]
+    fn spawn<F: FnOnce() -> Run + Send + 'static>(merge_runs: F) -> Self {
+        let (sender, receiver) = channel();
+        rayon::spawn(move || {
+            let run = panic::catch_unwind(panic::AssertUnwindSafe(merge_runs));
+            let _ = sender.send(run);
+        });
+        MergeHandle(Mutex::new(receiver))
+    }
+
+    #[cfg(not(feature = "parallel"))]
+    fn spawn<F: FnOnce() -> Run + Send + 'static>(merge_runs: F) -> Self {
+        MergeHandle(std::thread::spawn(merge_runs))
+    }
+
+    /// Get the merged run if the merge has finished, or self otherwise.
+    #[cfg(feature = "parallel")]
+    fn try_join(self) -> Result<Run, Self> {
+        let received = self.0.lock().unwrap().try_recv();
+        match received {
+            Ok(run) => Ok(run.unwrap_or_else(|payload| panic::resume_unwind(payload))),
+            Err(TryRecvError::Empty) => Err(self),
+            Err(TryRecvError::Disconnected) => unreachable!("Merge dropped its result"),
+        }
+    }
+
+    #[cfg(not(feature = "parallel"))]
+    fn try_join(self) -> Result<Run, Self> {
+        if self.0.is_finished() {
+            Ok(self.join())
+        } else {
+            Err(self)
+        }
+    }
+
+    /// Block until the merge has finished, resuming its panic if it had one.
+    #[cfg(feature = "parallel")]
+    fn join(self) -> Run {
+        let receiver = self.0.into_inner().unwrap();
+        let run = receiver.recv().expect("Merge dropped its result");
+        run.unwrap_or_else(|payload| panic::resume_unwind(payload))
+    }
+
+    #[cfg(not(feature = "parallel"))]
+    fn join(self) -> Run {
+        self.0
+            .join()
+            .unwrap_or_else(|payload| panic::resume_unwind(payload))
+    }
+}
+
+pub struct PackedRTreeLsm {
+    degree: usize,
+    next_index: usize,
+    // Largest first; only runs with non-empty trees are kept.
+    runs: Vec<Arc<Run>>,
+    // The height of the tallest run; shorter runs are padded to this height.
+    run_height: usize,
+    merge: Option<PendingMerge>,
+}
+
+impl RTree for PackedRTreeLsm {
+    fn is_empty(&self) -> bool {
+        self.runs.is_empty()
+    }
+
+    fn height(&self) -> usize {
+        if self.is_empty() {
+            0
+        } else {
+            self.run_height + self.num_virtual_levels()
+        }
+    }
+
+    fn degree(&self) -> usize {
+        self.degree
+    }
+
+    fn envelope(&self) -> Rectangle {
+        self.runs
+            .iter()
+            .fold(Rectangle::new_empty(), |envelope, run| {
+                envelope.merge(&run.tree.envelope())
+            })
+    }
+
+    fn query_rect_with_scratch<F>(
+        &self,
+        query: &Rectangle,
+        scratch: &mut QueryScratch,
+        mut visitor: F,
+    ) -> ControlFlow<()>
+    where
+        F: FnMut(usize) -> ControlFlow<()>,
+    {
+        for run in &self.runs {
+            run.tree
+                .query_rect_with_scratch(query, scratch, |i| visitor(run.indices[i]))?;
+        }
+        ControlFlow::Continue(())
+    }
+
+    fn query_point_with_scratch<F>(
+        &self,
+        point: Coordinate,
+        scratch: &mut QueryScratch,
+        mut visitor: F,
+    ) -> ControlFlow<()>
+    where
+        F: FnMut(usize) -> ControlFlow<()>,
+    {
+        for run in &self.runs {
+            run.tree
+                .query_point_with_scratch(point, scratch, |i| visitor(run.indices[i]))?;
+        }
+        ControlFlow::Continue(())
+    }
+
+    fn query_self_intersections(&self) -> Vec<(usize, usize)> {
+        let mut results = Vec::new();
+        for (i, run1) in self.runs.iter().enumerate() {
+            let pairs = run1.tree.query_self_intersections();
+            results.extend(
+                pairs
+                    .into_iter()
+                    .map(|(j1, j2)| (run1.indices[j1], run1.indices[j2])),
+            );
+            for run2 in &self.runs[(i + 1)..] {
+                let pairs = run1.tree.query_rtree_intersections(&run2.tree);
+                results.extend(
+                    pairs
+                        .into_iter()
+                        .map(|(j1, j2)| (run1.indices[j1], run2.indices[j2])),
+                );
+            }
+        }
+        results
+            .into_iter()
+            .map(|(i, j)| (i.min(j), i.max(j)))
+            .collect()
+    }
+
+    fn get_rectangle(&self, level: usize, offset: usize) -> Rectangle {
+        let run_height = self.run_height;
+        if level >= run_height {
+            // A virtual node, covering a range of runs.
+            let width = self.degree.pow((level + 1 - run_height) as u32);
+            let start = (width * offset).min(self.runs.len());
+            let end = (width * (offset + 1)).min(self.runs.len());
+            return self.runs[start..end]
+                .iter()
+                .fold(Rectangle::new_empty(), |envelope, run| {
+                    envelope.merge(&run.tree.envelope())
+                });
+        }
+
+        match self.locate(level, offset) {
+            Some((run, local_offset)) => {
+                let run_top = run.tree.height() - 1;
+                if level < run_top {
+                    run.tree.get_rectangle(level, local_offset)
+                } else if local_offset == 0 {
+                    // The root of a shorter run, padded up to run_height.
+                    run.tree.envelope()
+                } else {
+                    Rectangle::new_empty()
+                }
+            }
+            None => Rectangle::new_empty(),
+        }
+    }
+
+    fn get_index(&self, offset: usize) -> usize {
+        let (run, local_offset) = self.locate(0, offset).expect("No item at leaf offset");
+        run.indices[run.tree.get_index(local_offset)]
+    }
+}
+
+impl PackedRTreeLsm {
+    pub fn new_empty() -> Self {
+        Self::with_degree(DEFAULT_DEGREE)
+    }
+
+    /// An empty index whose runs have the given degree.
+    pub fn with_degree(degree: usize) -> Self {
+        PackedRTreeLsm {
+            degree: degree.max(2),
+            next_index: 0,
+            runs: Vec::new(),
+            run_height: 0,
+            merge: None,
+        }
+    }
+
+    /// Build an index with a single batch, so item i has index i.
+    pub fn new(degree: usize, items: &[impl HasEnvelope]) -> Self {
+        let mut lsm = Self::with_degree(degree);
+        lsm.append(items);
+        lsm
+    }
+
+    /**
+     * Add a batch of items as a new run, returning their indices.
+     *
+     * This may start merging runs in the background.  It only waits for a
+     * running merge if too many runs have been appended since it started.
+     */
+    pub fn append(&mut self, items: &[impl HasEnvelope]) -> Range<usize> {
+        let indices = self.next_index..(self.next_index + items.len());
+        self.next_index = indices.end;
+
+        let envelopes: Vec<Rectangle> = items.iter().map(|item| item.envelope()).collect();
+        let run = Run::new(self.degree, indices.clone().collect(), &envelopes);
+        let end = self.runs.len();
+        self.replace_runs(end..end, run);
+        self.poll_merge();
+        if let Some(merge) = &self.merge {
+            if self.runs.len() - merge.runs.end > MAX_UNMERGED_RUNS {
+                self.wait_for_merge();
+            }
+        }
+        indices
+    }
+
+    /// The number of runs, which is O(log n) once merges have finished.
+    pub fn num_runs(&self) -> usize {
+        self.runs.len()
+    }
+
+    /// Block until all pending merges have finished.
+    pub fn finish_merges(&mut self) {
+        while self.merge.is_some() {
+            self.wait_for_merge();
+        }
+    }
+
+    /// Block until the pending merge has finished, and start the next one.
+    fn wait_for_merge(&mut self) {
+        if let Some(merge) = self.merge.take() {
+            let run = merge.handle.join();
+            self.replace_runs(merge.runs, run);
+            self.poll_merge();
+        }
+    }
+
+    /// Replace the runs in range with run, unless it is empty.
+    fn replace_runs(&mut self, range: Range<usize>, run: Run) {
+        let run = if run.tree.is_empty() {
+            None
+        } else {
+            Some(Arc::new(run))
+        };
+        self.runs.splice(range, run);
+        self.run_height = self
+            .runs
+            .iter()
+            .map(|run| run.tree.height())
+            .max()
+            .unwrap_or(0);
+    }
+
+    /// Install finished merges, and start the next merge if one is needed.
+    fn poll_merge(&mut self) {
+        loop {
+            if let Some(merge) = self.merge.take() {
+                match merge.handle.try_join() {
+                    Ok(run) => self.replace_runs(merge.runs, run),
+                    Err(handle) => {
+                        self.merge = Some(PendingMerge {
+                            runs: merge.runs,
+                            handle,
+                        });
+                        return;
+                    }
+                }
+            }
+            if !self.start_merge() {
+                return;
+            }
+        }
+    }
+
+    /// Start merging the trailing runs, if they are too large.
+    fn start_merge(&mut self) -> bool {
+        let end = self.runs.len();
+        if self.merge.is_some() || end < 2 {
+            return false;
+        }
+
+        // Merge from the first run that is too small for the runs after it.
+        // Runs appended during a merge can be left behind a run that is
+        // large enough, so every run is checked.
+        let mut start = end;
+        let mut suffix_size = self.runs[end - 1].len();
+        for i in (0..(end - 1)).rev() {
+            if self.runs[i].len() < MERGE_RATIO * suffix_size {
+                start = i;
+            }
+            suffix_size += self.runs[i].len();
+        }
+        if start == end {
+            return false;
+        }
+
+        let degree = self.degree;
+        let runs: Vec<Arc<Run>> = self.runs[start..end].to_vec();
+        let handle = MergeHandle::spawn(move || Run::merge(degree, &runs));
+        self.merge = Some(PendingMerge {
+            runs: start..end,
+            handle,
+        });
+        true
+    }
+
+    /// The number of levels of virtual nodes needed above the runs.
+    fn num_virtual_levels(&self) -> usize {
+        let mut levels = 1;
+        while self.degree.pow(levels as u32) < self.runs.len() {
+            levels += 1;
+        }
+        levels
+    }
+
+    /// Find the run and offset within it of a node at level below run_height.
+    fn locate(&self, level: usize, offset: usize) -> Option<(&Run, usize)> {
+        let width = self.degree.pow((self.run_height - 1 - level) as u32);
+        let run = self.runs.get(offset / width)?;
+        let local_offset = offset % width;
+        let run_top = run.tree.height() - 1;
+        if level < run_top && local_offset >= self.degree.pow((run_top - level) as u32) {
+            return None;
+        }
+        Some((run, local_offset))
+    }
+}
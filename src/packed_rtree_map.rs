@@ -0,0 +1,159 @@
+/**
+ * A static Rtree that owns its items.
+ *
+ * The items are stored in the order of the leaves, so queries that return
+ * items read them with good locality.  As an RTree, it returns the indices
+ * of the items in their original order, like PackedRTree.
+ */
+use core::ops::ControlFlow;
+
+use crate::{
+    Coordinate, HasEnvelope, PackedRTree, QueryScratch, RTree, Rectangle, SpaceFillingCurve,
+};
+
+pub struct PackedRTreeMap<T> {
+    tree: PackedRTree,
+    // In leaf order
+    items: Vec<T>,
+}
+
+impl<T> RTree for PackedRTreeMap<T> {
+    fn is_empty(&self) -> bool {
+        self.tree.is_empty()
+    }
+
+    fn height(&self) -> usize {
+        self.tree.height()
+    }
+
+    fn degree(&self) -> usize {
+        self.tree.degree()
+    }
+
+    fn envelope(&self) -> Rectangle {
+        self.tree.envelope()
+    }
+
+    fn query_rect_with_scratch<F>(
+        &self,
+        query: &Rectangle,
+        scratch: &mut QueryScratch,
+        visitor: F,
+    ) -> ControlFlow<()>
+    where
+        F: FnMut(usize) -> ControlFlow<()>,
+    {
+        self.tree.query_rect_with_scratch(query, scratch, visitor)
+    }
+
+    fn query_point_with_scratch<F>(
+        &self,
+        point: Coordinate,
+        scratch: &mut QueryScratch,
+        visitor: F,
+    ) -> ControlFlow<()>
+    where
+        F: FnMut(usize) -> ControlFlow<()>,
+    {
+        self.tree.query_point_with_scratch(point, scratch, visitor)
+    }
+
+    fn query_self_intersections(&self) -> Vec<(usize, usize)> {
+        self.tree.query_self_intersections()
+    }
+
+    fn get_rectangle(&self, level: usize, offset: usize) -> Rectangle {
+        self.tree.get_rectangle(level, offset)
+    }
+
+    fn get_index(&self, offset: usize) -> usize {
+        self.tree.get_index(offset)
+    }
+}
+
+impl<T: HasEnvelope> PackedRTreeMap<T> {
+    pub fn new_empty() -> Self {
+        PackedRTreeMap {
+            tree: PackedRTree::new_empty(),
+            items: Vec::new(),
+        }
+    }
+
+    pub fn new_hilbert(degree: usize, items: Vec<T>) -> Self {
+        Self::from_tree(PackedRTree::new_hilbert(degree, &items), items)
+    }
+
+    pub fn new_sorted<C: SpaceFillingCurve>(degree: usize, items: Vec<T>) -> Self {
+        Self::from_tree(PackedRTree::new_sorted::<C>(degree, &items), items)
+    }
+
+    pub fn new_str(degree: usize, items: Vec<T>) -> Self {
+        Self::from_tree(PackedRTree::new_str(degree, &items), items)
+    }
+
+    pub fn new_omt(degree: usize, items: Vec<T>) -> Self {
+        Self::from_tree(PackedRTree::new_omt(degree, &items), items)
+    }
+
+    /// Take ownership of the items that tree was built from.
+    fn from_tree(tree: PackedRTree, items: Vec<T>) -> Self {
+        let shuffled_indices = tree.shuffled_indices();
+        if shuffled_indices.len() != items.len() {
+            // Only when no item has an envelope, so none can be found.
+            assert!(tree.is_empty());
+            return PackedRTreeMap { tree, items };
+        }
+
+        let mut slots: Vec<Option<T>> = items.into_iter().map(Some).collect();
+        let items = shuffled_indices
+            .iter()
+            .map(|&i| slots[i].take().unwrap())
+            .collect();
+        PackedRTreeMap { tree, items }
+    }
+}
+
+impl<T> PackedRTreeMap<T> {
+    /// The items, in leaf order.
+    pub fn items(&self) -> &[T] {
+        &self.items
+    }
+
+    /// Find the items that might intersect the query rect.
+    pub fn query_rect_items(&self, query: &Rectangle) -> Vec<&T> {
+        let mut results = Vec::new();
+        let _ = self.query_rect_items_with(query, |item| {
+            results.push(item);
+            ControlFlow::Continue(())
+        });
+        results
+    }
+
+    /// Visit the items that might intersect the query rect, stopping on Break.
+    pub fn query_rect_items_with<'a, F>(
+        &'a self,
+        query: &Rectangle,
+        mut visitor: F,
+    ) -> ControlFlow<()>
+    where
+        F: FnMut(&'a T) -> ControlFlow<()>,
+    {
+        self.tree
+            .raw_rtree()
+            .query_rect_with(query, |offset| visitor(&self.items[offset]))
+    }
+
+    /// Return the items in their original order.
+    pub fn into_inner(self) -> Vec<T> {
+        let shuffled_indices = self.tree.shuffled_indices();
+        if shuffled_indices.len() != self.items.len() {
+            return self.items;
+        }
+
+        let mut slots: Vec<Option<T>> = (0..self.items.len()).map(|_| None).collect();
+        for (&index, item) in shuffled_indices.iter().zip(self.items) {
+            slots[index] = Some(item);
+        }
+        slots.into_iter().map(Option::unwrap).collect()
+    }
+}
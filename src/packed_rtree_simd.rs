@@ -0,0 +1,258 @@
+/**
+ * A PackedRTreeSoa that checks children with explicit SIMD intrinsics.
+ *
+ * The children of a node are stored transposed, with each coordinate in its
+ * own contiguous lane, so one instruction compares a coordinate of 2 (SSE2)
+ * or 4 (AVX) children, and the hits are read from a combined mask.  The
+ * instruction set is chosen at construction by runtime CPU detection,
+ * falling back to scalar comparisons.
+ */
+#[cfg(target_arch = "x86")]
+use std::arch::x86::*;
+#[cfg(target_arch = "x86_64")]
+use std::arch::x86_64::*;
+
+use crate::{PackedRTreeSoa, QueryScratch, RTree, Rectangle};
+use core::ops::ControlFlow;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum SimdKernel {
+    Scalar,
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    Sse2,
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    Avx,
+}
+
+impl SimdKernel {
+    /// The fastest kernel supported by this CPU.
+    pub fn detect() -> Self {
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        {
+            if is_x86_feature_detected!("avx") {
+                return SimdKernel::Avx;
+            }
+            if is_x86_feature_detected!("sse2") {
+                return SimdKernel::Sse2;
+            }
+        }
+        SimdKernel::Scalar
+    }
+
+    /// All kernels supported by this CPU, slowest first.
+    pub fn available() -> Vec<Self> {
+        let mut kernels = vec![SimdKernel::Scalar];
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        {
+            if is_x86_feature_detected!("sse2") {
+                kernels.push(SimdKernel::Sse2);
+            }
+            if is_x86_feature_detected!("avx") {
+                kernels.push(SimdKernel::Avx);
+            }
+        }
+        kernels
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PackedRTreeSimd {
+    rtree: PackedRTreeSoa,
+    kernel: SimdKernel,
+}
+
+impl RTree for PackedRTreeSimd {
+    fn is_empty(&self) -> bool {
+        self.rtree.is_empty()
+    }
+
+    fn height(&self) -> usize {
+        self.rtree.height()
+    }
+
+    fn degree(&self) -> usize {
+        self.rtree.degree()
+    }
+
+    fn envelope(&self) -> Rectangle {
+        self.rtree.envelope()
+    }
+
+    fn query_rect_with_scratch<F>(
+        &self,
+        rect: &Rectangle,
+        scratch: &mut QueryScratch,
+        mut visitor: F,
+    ) -> ControlFlow<()>
+    where
+        F: FnMut(usize) -> ControlFlow<()>,
+    {
+        if self.is_empty() {
+            return ControlFlow::Continue(());
+        }
+
+        let stack = &mut scratch.stack;
+        stack.clear();
+        if self.envelope().intersects(rect) {
+            stack.push((self.height() - 1, 0));
+        }
+
+        while let Some((level, offset)) = stack.pop() {
+            if level == 0 {
+                visitor(offset)?;
+                continue;
+            }
+
+            let first_child_offset = self.degree() * offset;
+            let lanes = self.rtree.children_lanes(level - 1, first_child_offset);
+            let mut push = |inc| stack.push((level - 1, first_child_offset + inc));
+            match self.kernel {
+                SimdKernel::Scalar => check_children_scalar(lanes, 0, rect, &mut push),
+                // Safety: the kernel is only chosen if the CPU supports it.
+                #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+                SimdKernel::Sse2 => unsafe { check_children_sse2(lanes, rect, &mut push) },
+                #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+                SimdKernel::Avx => unsafe { check_children_avx(lanes, rect, &mut push) },
+            }
+        }
+
+        ControlFlow::Continue(())
+    }
+
+    fn query_self_intersections(&self) -> Vec<(usize, usize)> {
+        self.rtree.query_self_intersections()
+    }
+
+    fn get_rectangle(&self, level: usize, offset: usize) -> Rectangle {
+        self.rtree.get_rectangle(level, offset)
+    }
+
+    fn get_index(&self, offset: usize) -> usize {
+        self.rtree.get_index(offset)
+    }
+}
+
+impl PackedRTreeSimd {
+    pub fn new_empty() -> Self {
+        PackedRTreeSimd {
+            rtree: PackedRTreeSoa::new_empty(),
+            kernel: SimdKernel::detect(),
+        }
+    }
+
+    pub fn new(degree: usize, rects: &[Rectangle]) -> Self {
+        Self::new_with_kernel(degree, rects, SimdKernel::detect())
+    }
+
+    /// Build a tree that uses kernel, which must be supported by this CPU.
+    pub fn new_with_kernel(degree: usize, rects: &[Rectangle], kernel: SimdKernel) -> Self {
+        assert!(
+            SimdKernel::available().contains(&kernel),
+            "Kernel {:?} is not supported by this CPU",
+            kernel
+        );
+        PackedRTreeSimd {
+            rtree: PackedRTreeSoa::new(degree, rects),
+            kernel,
+        }
+    }
+
+    pub fn kernel(&self) -> SimdKernel {
+        self.kernel
+    }
+}
+
+/**
+ * Call push with the offset of each child from start on that intersects the
+ * query.  The lanes are [x_mins, y_mins, x_maxs, y_maxs].
+ */
+fn check_children_scalar(
+    lanes: [&[f64]; 4],
+    start: usize,
+    query: &Rectangle,
+    push: &mut impl FnMut(usize),
+) {
+    let [x_mins, y_mins, x_maxs, y_maxs] = lanes;
+    for inc in start..x_mins.len() {
+        if (x_mins[inc] <= query.x_max)
+            & (y_mins[inc] <= query.y_max)
+            & (x_maxs[inc] >= query.x_min)
+            & (y_maxs[inc] >= query.y_min)
+        {
+            push(inc);
+        }
+    }
+}
+
+/// Call push with first + i for each set bit i of mask, in order.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn push_mask(mut mask: i32, first: usize, push: &mut impl FnMut(usize)) {
+    while mask != 0 {
+        push(first + mask.trailing_zeros() as usize);
+        mask &= mask - 1;
+    }
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+#[target_feature(enable = "sse2")]
+unsafe fn check_children_sse2(lanes: [&[f64]; 4], query: &Rectangle, push: &mut impl FnMut(usize)) {
+    let [x_mins, y_mins, x_maxs, y_maxs] = lanes;
+    let query_x_min = _mm_set1_pd(query.x_min);
+    let query_y_min = _mm_set1_pd(query.y_min);
+    let query_x_max = _mm_set1_pd(query.x_max);
+    let query_y_max = _mm_set1_pd(query.y_max);
+    let num_vectors = x_mins.len() / 2;
+    for first in (0..num_vectors).map(|v| 2 * v) {
+        let hits = _mm_and_pd(
+            _mm_and_pd(
+                _mm_cmple_pd(_mm_loadu_pd(x_mins.as_ptr().add(first)), query_x_max),
+                _mm_cmple_pd(_mm_loadu_pd(y_mins.as_ptr().add(first)), query_y_max),
+            ),
+            _mm_and_pd(
+                _mm_cmpge_pd(_mm_loadu_pd(x_maxs.as_ptr().add(first)), query_x_min),
+                _mm_cmpge_pd(_mm_loadu_pd(y_maxs.as_ptr().add(first)), query_y_min),
+            ),
+        );
+        push_mask(_mm_movemask_pd(hits), first, push);
+    }
+    check_children_scalar(lanes, 2 * num_vectors, query, push);
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+#[target_feature(enable = "avx")]
+unsafe fn check_children_avx(lanes: [&[f64]; 4], query: &Rectangle, push: &mut impl FnMut(usize)) {
+    let [x_mins, y_mins, x_maxs, y_maxs] = lanes;
+    let query_x_min = _mm256_set1_pd(query.x_min);
+    let query_y_min = _mm256_set1_pd(query.y_min);
+    let query_x_max = _mm256_set1_pd(query.x_max);
+    let query_y_max = _mm256_set1_pd(query.y_max);
+    let num_vectors = x_mins.len() / 4;
+    for first in (0..num_vectors).map(|v| 4 * v) {
+        let x_min_hits = _mm256_cmp_pd(
+            _mm256_loadu_pd(x_mins.as_ptr().add(first)),
+            query_x_max,
+            _CMP_LE_OQ,
+        );
+        let y_min_hits = _mm256_cmp_pd(
+            _mm256_loadu_pd(y_mins.as_ptr().add(first)),
+            query_y_max,
+            _CMP_LE_OQ,
+        );
+        let x_max_hits = _mm256_cmp_pd(
+            _mm256_loadu_pd(x_maxs.as_ptr().add(first)),
+            query_x_min,
+            _CMP_GE_OQ,
+        );
+        let y_max_hits = _mm256_cmp_pd(
+            _mm256_loadu_pd(y_maxs.as_ptr().add(first)),
+            query_y_min,
+            _CMP_GE_OQ,
+        );
+        let hits = _mm256_and_pd(
+            _mm256_and_pd(x_min_hits, y_min_hits),
+            _mm256_and_pd(x_max_hits, y_max_hits),
+        );
+        push_mask(_mm256_movemask_pd(hits), first, push);
+    }
+    check_children_scalar(lanes, 4 * num_vectors, query, push);
+}
@@ -0,0 +1,230 @@
+/**
+ * A static Rtree with a structure-of-arrays node layout.
+ *
+ * Each coordinate of the node envelopes is stored in its own array, so the
+ * children of a node are contiguous per coordinate, and checking them
+ * vectorizes without any padding.
+ */
+use crate::utils::calculate_level_indices;
+use crate::{QueryScratch, RTree, Rectangle};
+use core::ops::{ControlFlow, Range};
+
+#[derive(Debug, Clone)]
+pub struct PackedRTreeSoa {
+    degree: usize,
+    size: usize,
+    level_indices: Vec<usize>,
+    // Empty slots have x_min = y_min = inf and x_max = y_max = -inf,
+    // so they never intersect anything.
+    x_mins: Vec<f64>,
+    y_mins: Vec<f64>,
+    x_maxs: Vec<f64>,
+    y_maxs: Vec<f64>,
+}
+
+impl RTree for PackedRTreeSoa {
+    fn is_empty(&self) -> bool {
+        self.size == 0
+    }
+
+    fn height(&self) -> usize {
+        self.level_indices.len()
+    }
+
+    fn degree(&self) -> usize {
+        self.degree
+    }
+
+    fn envelope(&self) -> Rectangle {
+        if self.is_empty() {
+            Rectangle::new_empty()
+        } else {
+            self.get_rectangle(self.height() - 1, 0)
+        }
+    }
+
+    fn query_rect_with_scratch<F>(
+        &self,
+        rect: &Rectangle,
+        scratch: &mut QueryScratch,
+        visitor: F,
+    ) -> ControlFlow<()>
+    where
+        F: FnMut(usize) -> ControlFlow<()>,
+    {
+        let root_matches = rect.intersects(&self.envelope());
+        self.visit(
+            root_matches,
+            scratch,
+            visitor,
+            |[x_mins, y_mins, x_maxs, y_maxs], inc| {
+                (x_mins[inc] <= rect.x_max)
+                    & (y_mins[inc] <= rect.y_max)
+                    & (x_maxs[inc] >= rect.x_min)
+                    & (y_maxs[inc] >= rect.y_min)
+            },
+        )
+    }
+
+    fn get_rectangle(&self, level: usize, offset: usize) -> Rectangle {
+        let index = self.level_indices[level] + offset;
+        if self.x_mins[index] > self.x_maxs[index] {
+            Rectangle::new_empty()
+        } else {
+            Rectangle {
+                x_min: self.x_mins[index],
+                y_min: self.y_mins[index],
+                x_max: self.x_maxs[index],
+                y_max: self.y_maxs[index],
+            }
+        }
+    }
+
+    fn get_index(&self, offset: usize) -> usize {
+        offset
+    }
+}
+
+impl PackedRTreeSoa {
+    pub fn new_empty() -> Self {
+        Self {
+            degree: 2,
+            size: 0,
+            level_indices: Vec::new(),
+            x_mins: Vec::new(),
+            y_mins: Vec::new(),
+            x_maxs: Vec::new(),
+            y_maxs: Vec::new(),
+        }
+    }
+
+    pub fn new(mut degree: usize, rects: &[Rectangle]) -> Self {
+        if rects.is_empty() {
+            return PackedRTreeSoa::new_empty();
+        }
+
+        degree = degree.max(2);
+        let size = rects.len();
+        let level_indices = calculate_level_indices(degree, size);
+        let tree_size = level_indices[level_indices.len() - 1] + 1;
+        let mut x_mins = vec![f64::INFINITY; tree_size];
+        let mut y_mins = vec![f64::INFINITY; tree_size];
+        let mut x_maxs = vec![f64::NEG_INFINITY; tree_size];
+        let mut y_maxs = vec![f64::NEG_INFINITY; tree_size];
+        for (i, rect) in rects.iter().enumerate() {
+            if rect.is_empty() {
+                continue;
+            }
+            x_mins[i] = rect.x_min;
+            y_mins[i] = rect.y_min;
+            x_maxs[i] = rect.x_max;
+            y_maxs[i] = rect.y_max;
+        }
+
+        for level in 1..level_indices.len() {
+            let previous_level = level_indices[level - 1]..level_indices[level];
+            let level_index = level_indices[level];
+            for (offset, first) in previous_level.step_by(degree).enumerate() {
+                let children = first..(first + degree);
+                let index = level_index + offset;
+                x_mins[index] = x_mins[children.clone()]
+                    .iter()
+                    .copied()
+                    .fold(f64::INFINITY, f64::min);
+                y_mins[index] = y_mins[children.clone()]
+                    .iter()
+                    .copied()
+                    .fold(f64::INFINITY, f64::min);
+                x_maxs[index] = x_maxs[children.clone()]
+                    .iter()
+                    .copied()
+                    .fold(f64::NEG_INFINITY, f64::max);
+                y_maxs[index] = y_maxs[children]
+                    .iter()
+                    .copied()
+                    .fold(f64::NEG_INFINITY, f64::max);
+            }
+        }
+
+        Self {
+            degree,
+            size,
+            level_indices,
+            x_mins,
+            y_mins,
+            x_maxs,
+            y_maxs,
+        }
+    }
+
+    /**
+     * Visit the leaves whose ancestors all match, if the root matches.
+     *
+     * matches is called with the lanes of a node's children and the position
+     * of a child in them.
+     */
+    fn visit<F, M>(
+        &self,
+        root_matches: bool,
+        scratch: &mut QueryScratch,
+        mut visitor: F,
+        matches: M,
+    ) -> ControlFlow<()>
+    where
+        F: FnMut(usize) -> ControlFlow<()>,
+        M: Fn([&[f64]; 4], usize) -> bool,
+    {
+        if self.is_empty() {
+            return ControlFlow::Continue(());
+        }
+
+        // Stack entries: (level, offset)
+        let stack = &mut scratch.stack;
+        stack.clear();
+        if root_matches {
+            stack.push((self.height() - 1, 0));
+        }
+
+        while let Some((level, offset)) = stack.pop() {
+            if level == 0 {
+                visitor(offset)?;
+                continue;
+            }
+
+            let child_level = level - 1;
+            let first_child_offset = self.degree * offset;
+            let lanes = self.children_lanes(child_level, first_child_offset);
+            for inc in 0..self.degree {
+                if matches(lanes, inc) {
+                    stack.push((child_level, first_child_offset + inc));
+                }
+            }
+        }
+
+        ControlFlow::Continue(())
+    }
+
+    /**
+     * The coordinates of the degree children starting at first_child_offset,
+     * as [x_mins, y_mins, x_maxs, y_maxs].
+     */
+    pub(crate) fn children_lanes(
+        &self,
+        child_level: usize,
+        first_child_offset: usize,
+    ) -> [&[f64]; 4] {
+        let children = self.children_range(child_level, first_child_offset);
+        [
+            &self.x_mins[children.clone()],
+            &self.y_mins[children.clone()],
+            &self.x_maxs[children.clone()],
+            &self.y_maxs[children],
+        ]
+    }
+
+    /// The index range of degree children starting at first_child_offset.
+    fn children_range(&self, child_level: usize, first_child_offset: usize) -> Range<usize> {
+        let first_child_index = self.level_indices[child_level] + first_child_offset;
+        first_child_index..(first_child_index + self.degree)
+    }
+}
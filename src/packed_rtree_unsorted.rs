@@ -5,8 +5,11 @@
  * anything explicit to enable vectorizatino.
  */
 use crate::utils::calculate_level_indices;
-use crate::{RTree, Rectangle};
-use core::ops::Range;
+use crate::{Coordinate, QueryScratch, RTree, Rectangle};
+use core::ops::{ControlFlow, Range};
+
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
 
 #[derive(Debug)]
 pub struct PackedRTreeUnsorted {
@@ -15,11 +18,18 @@ pub struct PackedRTreeUnsorted {
     // nodes in level i are (level_indices[i] .. level_indices[i + 1]) (end exclusive)
     level_indices: Vec<usize>,
     tree: Vec<Rectangle>,
+    // The number of leaves with empty envelopes, either when built or from
+    // removal, under each internal node, indexed like tree[level_indices[1]..].
+    // Ancestors of removed leaves keep their envelopes until refit.
+    empty_counts: Vec<u32>,
+    // Total area of the internal nodes when built, to judge refits against.
+    built_node_area: f64,
 }
 
 impl RTree for PackedRTreeUnsorted {
+    /// Whether there are no leaves, or every leaf has been removed.
     fn is_empty(&self) -> bool {
-        self.size == 0
+        self.size == 0 || self.has_only_empty_leaves()
     }
 
     fn height(&self) -> usize {
@@ -39,28 +49,53 @@ impl RTree for PackedRTreeUnsorted {
     }
 
     /**
-     * Find geometries that might intersect the query_rect.
+     * Visit geometries that might intersect the query_rect.
      *
      * This only checks bounding-box intersection, so the candidates must be
      * checked by the caller.
      */
-    fn query_rect(&self, query: &Rectangle) -> Vec<usize> {
-        let mut results = Vec::new();
-        if self.is_empty() {
-            return results;
-        }
+    fn query_rect_with_scratch<F>(
+        &self,
+        query: &Rectangle,
+        scratch: &mut QueryScratch,
+        mut visitor: F,
+    ) -> ControlFlow<()>
+    where
+        F: FnMut(usize) -> ControlFlow<()>,
+    {
+        self.visit_rect_ranges(query, &mut scratch.stack, |result| {
+            for index in result {
+                visitor(index)?;
+            }
+            ControlFlow::Continue(())
+        })
+    }
 
-        let mut stack = Vec::new();
-        if query.intersects(&self.envelope()) {
-            stack.push(self.root());
+    /**
+     * Visit geometries whose envelopes might contain point.
+     *
+     * Only degenerate nodes can be contained by a point, so unlike
+     * query_rect_with_scratch, this doesn't check for contained ranges.
+     */
+    fn query_point_with_scratch<F>(
+        &self,
+        point: Coordinate,
+        scratch: &mut QueryScratch,
+        mut visitor: F,
+    ) -> ControlFlow<()>
+    where
+        F: FnMut(usize) -> ControlFlow<()>,
+    {
+        if self.is_empty() || !self.envelope().contains_point(point) {
+            return ControlFlow::Continue(());
         }
 
-        // The todo_list will keep a LIFO stack of nodes to be processed.
-        // The invariant is that everything in todo_list (envelope) intersects
-        // query_rect.
+        let stack = &mut scratch.stack;
+        stack.clear();
+        stack.push(self.root());
         while let Some((level, offset)) = stack.pop() {
             if level == 0 {
-                results.push(offset);
+                visitor(offset)?;
                 continue;
             }
 
@@ -68,21 +103,22 @@ impl RTree for PackedRTreeUnsorted {
             let first_child_offset = self.degree * offset;
             let first_child_index = self.level_indices[child_level] + first_child_offset;
             let children = &self.tree[first_child_index..(first_child_index + self.degree)];
-
             for (inc, child) in children.iter().enumerate() {
-                if query.intersects(child) {
-                    let child_offset = first_child_offset + inc;
-                    if query.contains(child) {
-                        // If it contains the node, it contains (and intersects) all the leaves
-                        results.extend(self.get_leaf_range(child_level, child_offset))
-                    } else {
-                        stack.push((child_level, child_offset));
-                    }
+                if child.contains_point(point) {
+                    stack.push((child_level, first_child_offset + inc));
                 }
             }
         }
 
-        results
+        ControlFlow::Continue(())
+    }
+
+    fn get_rectangle(&self, level: usize, offset: usize) -> Rectangle {
+        self.tree[self.level_indices[level] + offset]
+    }
+
+    fn get_index(&self, offset: usize) -> usize {
+        offset
     }
 }
 
@@ -104,6 +140,8 @@ impl PackedRTreeUnsorted {
             size: 0,
             level_indices: Vec::new(),
             tree: Vec::new(),
+            empty_counts: Vec::new(),
+            built_node_area: 0.,
         }
     }
 
@@ -124,40 +162,311 @@ impl PackedRTreeUnsorted {
             assert_eq!(rects.len(), level_index);
 
             let level_rects = &rects[level_indices[level - 1]..level_indices[level]];
-            let next_rects: Vec<Rectangle> = level_rects
-                .chunks(degree)
-                .map(|rects| Rectangle::of(rects))
-                .collect();
+            #[cfg(feature = "parallel")]
+            let level_chunks = level_rects.par_chunks(degree);
+            #[cfg(not(feature = "parallel"))]
+            let level_chunks = level_rects.chunks(degree);
+            let next_rects: Vec<Rectangle> =
+                level_chunks.map(|rects| Rectangle::of(rects)).collect();
             rects.extend(next_rects);
         }
 
         rects.shrink_to_fit();
+        let empty_counts = count_empty_leaves(degree, size, &level_indices, &rects);
 
-        Self {
+        let mut rtree = Self {
             degree,
             size,
             level_indices,
             tree: rects,
+            empty_counts,
+            built_node_area: 0.,
+        };
+        rtree.built_node_area = rtree.node_area();
+        rtree
+    }
+
+    /**
+     * Remove the leaf at index, returning whether it was present.
+     *
+     * The leaf is marked dead by emptying its envelope, so queries skip it.
+     * Ancestor envelopes are not shrunk until refit is called.
+     */
+    pub fn remove(&mut self, index: usize) -> bool {
+        if index >= self.size || self.tree[index].is_empty() {
+            return false;
         }
+        self.tree[index] = Rectangle::new_empty();
+        self.for_each_empty_count(index, |count| *count += 1);
+        true
+    }
+
+    /**
+     * Change the envelope of the leaf at index, keeping its place in the tree,
+     * returning whether there is a leaf at index.
+     *
+     * Ancestor envelopes are expanded to cover the new envelope, so queries
+     * stay correct, but they are not shrunk until refit is called.  Setting
+     * an empty envelope removes the leaf.
+     */
+    pub fn update_envelope(&mut self, index: usize, envelope: Rectangle) -> bool {
+        if index >= self.size {
+            return false;
+        }
+        match (self.tree[index].is_empty(), envelope.is_empty()) {
+            (true, false) => self.for_each_empty_count(index, |count| *count -= 1),
+            (false, true) => self.for_each_empty_count(index, |count| *count += 1),
+            _ => (),
+        }
+        self.tree[index] = envelope;
+
+        let mut offset = index;
+        for level in 1..self.level_indices.len() {
+            offset /= self.degree;
+            self.tree[self.level_indices[level] + offset].expand(&envelope);
+        }
+        true
+    }
+
+    /**
+     * Recompute the envelopes of all nodes from their children, bottom-up.
+     *
+     * The layout is unchanged, so if leaves have moved far, the nodes may
+     * overlap much more than a fresh build; the report says how much.
+     */
+    pub fn refit(&mut self) -> RefitReport {
+        for level in 1..self.level_indices.len() {
+            let (lower, upper) = self.tree.split_at_mut(self.level_indices[level]);
+            let children = &lower[self.level_indices[level - 1]..];
+            for (node, children) in upper.iter_mut().zip(children.chunks(self.degree)) {
+                *node = Rectangle::of(children);
+            }
+        }
+        RefitReport {
+            node_area: self.node_area(),
+            built_node_area: self.built_node_area,
+        }
+    }
+
+    /// Call f on the empty leaf count of each ancestor of the leaf at index.
+    fn for_each_empty_count(&mut self, index: usize, mut f: impl FnMut(&mut u32)) {
+        let mut offset = index;
+        for level in 1..self.level_indices.len() {
+            offset /= self.degree;
+            let count_index = self.level_indices[level] - self.level_indices[1] + offset;
+            f(&mut self.empty_counts[count_index]);
+        }
+    }
+
+    /// Whether any leaf under the node has an empty envelope.
+    fn has_empty_leaves(&self, level: usize, offset: usize) -> bool {
+        if level == 0 {
+            self.tree[offset].is_empty()
+        } else {
+            self.empty_counts[self.level_indices[level] - self.level_indices[1] + offset] > 0
+        }
+    }
+
+    /// Whether every leaf has an empty envelope; the root count is last.
+    fn has_only_empty_leaves(&self) -> bool {
+        match self.empty_counts.last() {
+            Some(&count) => count as usize == self.size,
+            None => self.tree[0].is_empty(),
+        }
+    }
+
+    /// Total area of the internal nodes.
+    fn node_area(&self) -> f64 {
+        if self.level_indices.len() < 2 {
+            return 0.;
+        }
+        self.tree[self.level_indices[1]..]
+            .iter()
+            .filter(|rect| !rect.is_empty())
+            .map(|rect| (rect.x_max - rect.x_min) * (rect.y_max - rect.y_min))
+            .sum()
     }
 
     pub(crate) fn root(&self) -> (usize, usize) {
         (self.height() - 1, 0)
     }
 
+    /**
+     * Find geometries that might intersect the query_rect, reporting the
+     * leaves of fully-contained nodes as a single range.
+     *
+     * This is proportional to the number of nodes touched, rather than the
+     * number of results.  Ranges only cover nodes with no empty leaves, so
+     * removed leaves are never reported.  Use `.into_iter().flatten()` to
+     * get individual indices.
+     */
+    pub fn query_rect_ranges(&self, query: &Rectangle) -> Vec<QueryResult> {
+        let mut results = Vec::new();
+        let _ = self.visit_rect_ranges(query, &mut Vec::new(), |result| {
+            results.push(result);
+            ControlFlow::Continue(())
+        });
+        results
+    }
+
+    fn visit_rect_ranges<F>(
+        &self,
+        query: &Rectangle,
+        stack: &mut Vec<(usize, usize)>,
+        mut visitor: F,
+    ) -> ControlFlow<()>
+    where
+        F: FnMut(QueryResult) -> ControlFlow<()>,
+    {
+        if self.is_empty() {
+            return ControlFlow::Continue(());
+        }
+
+        // Ranges are only reported for nodes without empty leaves, which
+        // could have been removed.
+        let (root_level, root_offset) = self.root();
+        stack.clear();
+        if query.contains(&self.envelope()) && !self.has_empty_leaves(root_level, root_offset) {
+            return visitor(QueryResult::Range(
+                self.get_leaf_range(root_level, root_offset),
+            ));
+        } else if query.intersects(&self.envelope()) {
+            stack.push(self.root());
+        }
+
+        // The todo_list will keep a LIFO stack of nodes to be processed.
+        // The invariant is that everything in todo_list (envelope) intersects
+        // query_rect.
+        while let Some((level, offset)) = stack.pop() {
+            if level == 0 {
+                visitor(QueryResult::Index(offset))?;
+                continue;
+            }
+
+            let child_level = level - 1;
+            let first_child_offset = self.degree * offset;
+            let first_child_index = self.level_indices[child_level] + first_child_offset;
+            let children = &self.tree[first_child_index..(first_child_index + self.degree)];
+
+            for (inc, child) in children.iter().enumerate() {
+                if query.intersects(child) {
+                    let child_offset = first_child_offset + inc;
+                    if child_level == 0 {
+                        visitor(QueryResult::Index(child_offset))?;
+                    } else if query.contains(child)
+                        && !self.has_empty_leaves(child_level, child_offset)
+                    {
+                        // If it contains the node, it contains (and intersects) all the leaves
+                        visitor(QueryResult::Range(
+                            self.get_leaf_range(child_level, child_offset),
+                        ))?;
+                    } else {
+                        stack.push((child_level, child_offset));
+                    }
+                }
+            }
+        }
+
+        ControlFlow::Continue(())
+    }
+
     /// Get the index range for leaf nodes under this node.
-    pub(crate) fn get_leaf_range(&self, level: usize, offset: usize) -> Vec<usize> {
-        // pub(crate) fn get_leaf_range(&self, level: usize, offset: usize) -> Range<usize> {
+    pub(crate) fn get_leaf_range(&self, level: usize, offset: usize) -> Range<usize> {
         let width = self.degree.pow(level as u32);
-        let range = Range {
+        Range {
             start: width * offset,
             // index is for coordinates, and coordinates.len() == rectangles.len() + 1
             end: self.size.min(width * (offset + 1)),
-        };
-        let result: Vec<usize> = range
-            .into_iter()
-            .filter(|i| !self.tree[*i].is_empty())
-            .collect();
-        result
+        }
+    }
+}
+
+/**
+ * How much a refit tree has degraded since it was built.
+ *
+ * The expected number of nodes a small query visits grows with the total
+ * area of the nodes, so once that has grown well past its size when built,
+ * rebuilding the tree (for example with new_hilbert) will pay off.
+ */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RefitReport {
+    pub node_area: f64,
+    pub built_node_area: f64,
+}
+
+impl RefitReport {
+    /// Total node area is this multiple of the built area before we rebuild.
+    const REBUILD_RATIO: f64 = 2.;
+
+    /// The ratio of the current total node area to the area when built.
+    pub fn degradation(&self) -> f64 {
+        if self.built_node_area > 0. {
+            self.node_area / self.built_node_area
+        } else if self.node_area > 0. {
+            f64::INFINITY
+        } else {
+            1.
+        }
+    }
+
+    pub fn should_rebuild(&self) -> bool {
+        self.degradation() > Self::REBUILD_RATIO
+    }
+}
+
+/// A query result: either a single leaf index, or a contiguous range of them.
+#[derive(Clone, Debug, PartialEq)]
+pub enum QueryResult {
+    Index(usize),
+    Range(Range<usize>),
+}
+
+impl QueryResult {
+    pub fn len(&self) -> usize {
+        match self {
+            QueryResult::Index(_) => 1,
+            QueryResult::Range(range) => range.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl IntoIterator for QueryResult {
+    type Item = usize;
+    type IntoIter = Range<usize>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        match self {
+            QueryResult::Index(index) => index..(index + 1),
+            QueryResult::Range(range) => range,
+        }
+    }
+}
+
+/// The number of the first size leaves with empty envelopes under each internal node.
+fn count_empty_leaves(
+    degree: usize,
+    size: usize,
+    level_indices: &[usize],
+    tree: &[Rectangle],
+) -> Vec<u32> {
+    if level_indices.len() < 2 {
+        return Vec::new();
+    }
+
+    let mut counts = vec![0; tree.len() - level_indices[1]];
+    for (count, leaves) in counts.iter_mut().zip(tree[..size].chunks(degree)) {
+        *count = leaves.iter().filter(|leaf| leaf.is_empty()).count() as u32;
+    }
+    for level in 2..level_indices.len() {
+        let (lower, upper) = counts.split_at_mut(level_indices[level] - level_indices[1]);
+        let children = &lower[(level_indices[level - 1] - level_indices[1])..];
+        for (count, children) in upper.iter_mut().zip(children.chunks(degree)) {
+            *count = children.iter().sum();
+        }
     }
+    counts
 }
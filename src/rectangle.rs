@@ -108,6 +108,33 @@ impl Rectangle {
             && self.y_max >= other.y_max
     }
 
+    /// Whether coord is in the rectangle, including its boundary.
+    pub fn contains_point(&self, coord: Coordinate) -> bool {
+        self.x_min <= coord.x
+            && coord.x <= self.x_max
+            && self.y_min <= coord.y
+            && coord.y <= self.y_max
+    }
+
+    /// Squared distance from coord to the closest point of the rectangle.
+    pub fn distance_squared(&self, coord: Coordinate) -> f64 {
+        let dx = (self.x_min - coord.x).max(coord.x - self.x_max).max(0.);
+        let dy = (self.y_min - coord.y).max(coord.y - self.y_max).max(0.);
+        dx * dx + dy * dy
+    }
+
+    /// Squared distance between the closest points of the two rectangles.
+    pub fn distance_squared_to<T: HasEnvelope>(&self, item: &T) -> f64 {
+        let other = item.envelope();
+        let dx = (other.x_min - self.x_max)
+            .max(self.x_min - other.x_max)
+            .max(0.);
+        let dy = (other.y_min - self.y_max)
+            .max(self.y_min - other.y_max)
+            .max(0.);
+        dx * dx + dy * dy
+    }
+
     #[must_use = "this returns the result of the operation, without modifying the original"]
     pub fn merge<T: HasEnvelope>(&self, item: &T) -> Self {
         let rect = item.envelope();
@@ -0,0 +1,504 @@
+/**
+ * A dynamic Rtree, supporting insertion and removal, using the R*-tree
+ * heuristics for choosing subtrees, forced reinsertion, and splitting.
+ *
+ * Nodes live in an arena and hold between min_entries and degree children.
+ * For the RTree trait, offsets above the leaves are node ids in the arena,
+ * and offsets of leaves are item indices.
+ */
+use core::ops::ControlFlow;
+
+use crate::utils::total_cmp;
+use crate::{HasEnvelope, QueryScratch, RTree, Rectangle};
+
+const DEFAULT_DEGREE: usize = 16;
+// With fewer children, a split could leave a node with a single child.
+const MIN_DEGREE: usize = 4;
+
+#[derive(Debug, Clone)]
+struct Node {
+    // Leaf nodes are level 1, and hold item indices.  Otherwise children are
+    // node ids in the arena.
+    level: usize,
+    envelope: Rectangle,
+    children: Vec<usize>,
+}
+
+#[derive(Debug, Clone)]
+pub struct RStarTree {
+    degree: usize,
+    min_entries: usize,
+    reinsert_count: usize,
+    size: usize,
+    root: usize,
+    nodes: Vec<Node>,
+    free_nodes: Vec<usize>,
+    // Envelope of each item by index; empty if it is not in the tree.
+    envelopes: Vec<Rectangle>,
+}
+
+impl RTree for RStarTree {
+    fn is_empty(&self) -> bool {
+        self.size == 0
+    }
+
+    fn height(&self) -> usize {
+        if self.is_empty() {
+            0
+        } else {
+            self.nodes[self.root].level + 1
+        }
+    }
+
+    fn degree(&self) -> usize {
+        self.degree
+    }
+
+    fn envelope(&self) -> Rectangle {
+        if self.is_empty() {
+            Rectangle::new_empty()
+        } else {
+            self.nodes[self.root].envelope
+        }
+    }
+
+    fn query_rect_with_scratch<F>(
+        &self,
+        query: &Rectangle,
+        scratch: &mut QueryScratch,
+        visitor: F,
+    ) -> ControlFlow<()>
+    where
+        F: FnMut(usize) -> ControlFlow<()>,
+    {
+        self.visit(|envelope| query.intersects(envelope), scratch, visitor)
+    }
+
+    fn get_rectangle(&self, level: usize, offset: usize) -> Rectangle {
+        if level == 0 {
+            self.envelopes[offset]
+        } else {
+            self.nodes[offset].envelope
+        }
+    }
+
+    fn get_index(&self, offset: usize) -> usize {
+        offset
+    }
+
+    fn root_offset(&self) -> usize {
+        self.root
+    }
+
+    fn child_offset(&self, _level: usize, offset: usize, position: usize) -> Option<usize> {
+        self.nodes[offset].children.get(position).copied()
+    }
+}
+
+impl RStarTree {
+    pub fn new_empty() -> Self {
+        Self::with_degree(DEFAULT_DEGREE)
+    }
+
+    /// An empty tree whose nodes have at most degree children, and degree is at least 4.
+    pub fn with_degree(degree: usize) -> Self {
+        let degree = degree.max(MIN_DEGREE);
+        RStarTree {
+            degree,
+            // The R*-tree paper recommends 40% minimum fill, but at least 2,
+            // and reinserting 30%.
+            min_entries: (degree * 2 / 5).max(2),
+            reinsert_count: (degree * 3 / 10).max(1),
+            size: 0,
+            root: 0,
+            nodes: vec![Node {
+                level: 1,
+                envelope: Rectangle::new_empty(),
+                children: Vec::new(),
+            }],
+            free_nodes: Vec::new(),
+            envelopes: Vec::new(),
+        }
+    }
+
+    /// Build a tree by inserting items in order, so item i has index i.
+    pub fn new(degree: usize, items: &[impl HasEnvelope]) -> Self {
+        let mut tree = Self::with_degree(degree);
+        for item in items {
+            tree.insert(item);
+        }
+        tree
+    }
+
+    /**
+     * Insert an item, returning its index.
+     *
+     * Indices are assigned in order of insertion, and are not reused.  Items
+     * with empty envelopes get an index, but are never found by queries.
+     */
+    pub fn insert(&mut self, item: &impl HasEnvelope) -> usize {
+        let index = self.envelopes.len();
+        let envelope = item.envelope();
+        self.envelopes.push(envelope);
+        if !envelope.is_empty() {
+            self.size += 1;
+            self.insert_entries(vec![(1, index)]);
+        }
+        index
+    }
+
+    /// Remove the item with index from the tree, returning whether it was present.
+    pub fn remove(&mut self, index: usize) -> bool {
+        let envelope = match self.envelopes.get(index) {
+            Some(envelope) if !envelope.is_empty() => *envelope,
+            _ => return false,
+        };
+        let path = match self.find_leaf(index, &envelope) {
+            Some(path) => path,
+            None => return false,
+        };
+        self.envelopes[index] = Rectangle::new_empty();
+        self.size -= 1;
+
+        let leaf = *path.last().unwrap();
+        self.nodes[leaf].children.retain(|&child| child != index);
+
+        // Condense the tree: remove underfull nodes below the root, and
+        // reinsert their children at the same level.
+        let mut orphans = Vec::new();
+        for i in (1..path.len()).rev() {
+            let node_id = path[i];
+            if self.nodes[node_id].children.len() < self.min_entries {
+                let parent = path[i - 1];
+                self.nodes[parent]
+                    .children
+                    .retain(|&child| child != node_id);
+                let level = self.nodes[node_id].level;
+                let children = std::mem::take(&mut self.nodes[node_id].children);
+                orphans.extend(children.into_iter().map(|child| (level, child)));
+                self.free_nodes.push(node_id);
+            } else {
+                self.update_envelope(node_id);
+            }
+        }
+        self.update_envelope(self.root);
+        self.insert_entries(orphans);
+
+        // Shorten the tree while the root has a single child node.
+        while self.nodes[self.root].level > 1 && self.nodes[self.root].children.len() == 1 {
+            self.free_nodes.push(self.root);
+            self.root = self.nodes[self.root].children[0];
+        }
+        true
+    }
+
+    /**
+     * Insert (level, child) entries, where child belongs in a node of level.
+     *
+     * Each level is only force-reinserted once for a batch, after which
+     * overflowing nodes are split.
+     */
+    fn insert_entries(&mut self, mut pending: Vec<(usize, usize)>) {
+        let mut reinserted = Vec::new();
+        while let Some((level, child)) = pending.pop() {
+            let rect = self.child_envelope(level, child);
+            let path = self.choose_path(&rect, level);
+            let node_id = *path.last().unwrap();
+            self.nodes[node_id].children.push(child);
+            for &ancestor in &path {
+                self.nodes[ancestor].envelope = self.nodes[ancestor].envelope.merge(&rect);
+            }
+            self.treat_overflow(&path, &mut reinserted, &mut pending);
+        }
+    }
+
+    /// Fix overflowing nodes along path, from the bottom up.
+    fn treat_overflow(
+        &mut self,
+        path: &[usize],
+        reinserted: &mut Vec<bool>,
+        pending: &mut Vec<(usize, usize)>,
+    ) {
+        for i in (0..path.len()).rev() {
+            let node_id = path[i];
+            if self.nodes[node_id].children.len() <= self.degree {
+                return;
+            }
+
+            let level = self.nodes[node_id].level;
+            if reinserted.len() <= level {
+                reinserted.resize(level + 1, false);
+            }
+            if i > 0 && !reinserted[level] {
+                reinserted[level] = true;
+                let removed = self.take_farthest(node_id);
+                for &ancestor in path[..i].iter().rev() {
+                    self.update_envelope(ancestor);
+                }
+                // Pending is a stack, so the closest entries are reinserted first.
+                pending.extend(removed.into_iter().map(|child| (level, child)));
+                return;
+            }
+
+            let new_node = self.split(node_id);
+            if i == 0 {
+                let envelope = self.nodes[node_id]
+                    .envelope
+                    .merge(&self.nodes[new_node].envelope);
+                self.root = self.alloc_node(Node {
+                    level: level + 1,
+                    envelope,
+                    children: vec![node_id, new_node],
+                });
+                return;
+            }
+            self.nodes[path[i - 1]].children.push(new_node);
+        }
+    }
+
+    /// Find the path from the root to the node at level that rect should be added to.
+    fn choose_path(&self, rect: &Rectangle, level: usize) -> Vec<usize> {
+        let mut path = vec![self.root];
+        let mut node_id = self.root;
+        while self.nodes[node_id].level > level {
+            let node = &self.nodes[node_id];
+            let child_level = node.level - 1;
+            let envelopes: Vec<Rectangle> = node
+                .children
+                .iter()
+                .map(|&child| self.nodes[child].envelope)
+                .collect();
+            let cost = |i: usize| {
+                let envelope = &envelopes[i];
+                let merged = envelope.merge(rect);
+                let enlargement = area(&merged) - area(envelope);
+                // Above the leaves, overlap is too expensive to compute.
+                let overlap_enlargement = if child_level == 1 {
+                    envelopes
+                        .iter()
+                        .enumerate()
+                        .filter(|&(j, _)| j != i)
+                        .map(|(_, other)| overlap(&merged, other) - overlap(envelope, other))
+                        .sum()
+                } else {
+                    0.
+                };
+                (overlap_enlargement, enlargement, area(envelope))
+            };
+            let best = (0..envelopes.len())
+                .map(|i| (cost(i), i))
+                .min_by(|(cost1, _), (cost2, _)| {
+                    total_cmp(cost1.0, cost2.0)
+                        .then(total_cmp(cost1.1, cost2.1))
+                        .then(total_cmp(cost1.2, cost2.2))
+                })
+                .map(|(_, i)| i)
+                .unwrap();
+            node_id = node.children[best];
+            path.push(node_id);
+        }
+        path
+    }
+
+    /// Remove the children farthest from the node's center, farthest first.
+    fn take_farthest(&mut self, node_id: usize) -> Vec<usize> {
+        let level = self.nodes[node_id].level;
+        let center = self.nodes[node_id].envelope.center();
+        let mut children = std::mem::take(&mut self.nodes[node_id].children);
+        let distance = |child: usize| {
+            let child_center = self.child_envelope(level, child).center();
+            let (dx, dy) = (child_center.x - center.x, child_center.y - center.y);
+            dx * dx + dy * dy
+        };
+        children.sort_by(|&c1, &c2| total_cmp(distance(c2), distance(c1)));
+        let kept = children.split_off(self.reinsert_count);
+        self.nodes[node_id].children = kept;
+        self.update_envelope(node_id);
+        children
+    }
+
+    /**
+     * Split an overflowing node, returning the id of the new sibling.
+     *
+     * The split axis minimizes the sum of margins over all distributions,
+     * and the distribution along it minimizes overlap, then area.
+     */
+    fn split(&mut self, node_id: usize) -> usize {
+        let level = self.nodes[node_id].level;
+        let children = std::mem::take(&mut self.nodes[node_id].children);
+        let mut entries: Vec<(usize, Rectangle)> = children
+            .into_iter()
+            .map(|child| (child, self.child_envelope(level, child)))
+            .collect();
+        let num_entries = entries.len();
+        let splits = self.min_entries..=(num_entries - self.min_entries);
+
+        let sort_x = |entries: &mut Vec<(usize, Rectangle)>| {
+            entries.sort_by(|(_, r1), (_, r2)| {
+                total_cmp(r1.x_min, r2.x_min).then(total_cmp(r1.x_max, r2.x_max))
+            })
+        };
+        let sort_y = |entries: &mut Vec<(usize, Rectangle)>| {
+            entries.sort_by(|(_, r1), (_, r2)| {
+                total_cmp(r1.y_min, r2.y_min).then(total_cmp(r1.y_max, r2.y_max))
+            })
+        };
+        let margin_sum = |entries: &[(usize, Rectangle)]| -> f64 {
+            splits
+                .clone()
+                .map(|k| {
+                    let (first, second) = split_envelopes(entries, k);
+                    margin(&first) + margin(&second)
+                })
+                .sum()
+        };
+
+        sort_x(&mut entries);
+        let x_margin = margin_sum(&entries);
+        sort_y(&mut entries);
+        let y_margin = margin_sum(&entries);
+        if x_margin < y_margin {
+            sort_x(&mut entries);
+        }
+
+        let split_at = splits
+            .clone()
+            .min_by(|&k1, &k2| {
+                let (first1, second1) = split_envelopes(&entries, k1);
+                let (first2, second2) = split_envelopes(&entries, k2);
+                total_cmp(overlap(&first1, &second1), overlap(&first2, &second2)).then(total_cmp(
+                    area(&first1) + area(&second1),
+                    area(&first2) + area(&second2),
+                ))
+            })
+            .unwrap();
+
+        let (first_envelope, second_envelope) = split_envelopes(&entries, split_at);
+        let second = entries.split_off(split_at);
+        self.nodes[node_id].children = entries.into_iter().map(|(child, _)| child).collect();
+        self.nodes[node_id].envelope = first_envelope;
+        self.alloc_node(Node {
+            level,
+            envelope: second_envelope,
+            children: second.into_iter().map(|(child, _)| child).collect(),
+        })
+    }
+
+    /// Find the path from the root to the leaf node holding index.
+    fn find_leaf(&self, index: usize, envelope: &Rectangle) -> Option<Vec<usize>> {
+        let mut stack = vec![vec![self.root]];
+        while let Some(path) = stack.pop() {
+            let node = &self.nodes[*path.last().unwrap()];
+            if node.level == 1 {
+                if node.children.contains(&index) {
+                    return Some(path);
+                }
+                continue;
+            }
+            for &child in &node.children {
+                if self.nodes[child].envelope.contains(envelope) {
+                    let mut child_path = path.clone();
+                    child_path.push(child);
+                    stack.push(child_path);
+                }
+            }
+        }
+        None
+    }
+
+    /// Visit the items whose envelope, and every ancestor's, matches.
+    fn visit<F, M>(&self, matches: M, scratch: &mut QueryScratch, mut visitor: F) -> ControlFlow<()>
+    where
+        F: FnMut(usize) -> ControlFlow<()>,
+        M: Fn(&Rectangle) -> bool,
+    {
+        if self.is_empty() || !matches(&self.envelope()) {
+            return ControlFlow::Continue(());
+        }
+
+        // Stack entries: (level, node id)
+        let stack = &mut scratch.stack;
+        stack.clear();
+        stack.push((self.nodes[self.root].level, self.root));
+
+        while let Some((level, node_id)) = stack.pop() {
+            let node = &self.nodes[node_id];
+            for &child in &node.children {
+                if !matches(&self.child_envelope(level, child)) {
+                    continue;
+                }
+                if level == 1 {
+                    visitor(child)?;
+                } else {
+                    stack.push((level - 1, child));
+                }
+            }
+        }
+
+        ControlFlow::Continue(())
+    }
+
+    /// Envelope of a child of a node at level.
+    fn child_envelope(&self, level: usize, child: usize) -> Rectangle {
+        if level == 1 {
+            self.envelopes[child]
+        } else {
+            self.nodes[child].envelope
+        }
+    }
+
+    fn update_envelope(&mut self, node_id: usize) {
+        let node = &self.nodes[node_id];
+        let envelope = node
+            .children
+            .iter()
+            .fold(Rectangle::new_empty(), |envelope, &child| {
+                envelope.merge(&self.child_envelope(node.level, child))
+            });
+        self.nodes[node_id].envelope = envelope;
+    }
+
+    fn alloc_node(&mut self, node: Node) -> usize {
+        match self.free_nodes.pop() {
+            Some(node_id) => {
+                self.nodes[node_id] = node;
+                node_id
+            }
+            None => {
+                self.nodes.push(node);
+                self.nodes.len() - 1
+            }
+        }
+    }
+}
+
+fn area(rect: &Rectangle) -> f64 {
+    (rect.x_max - rect.x_min) * (rect.y_max - rect.y_min)
+}
+
+fn margin(rect: &Rectangle) -> f64 {
+    (rect.x_max - rect.x_min) + (rect.y_max - rect.y_min)
+}
+
+/// Area of the intersection of two rectangles, or 0 if they don't intersect.
+fn overlap(rect1: &Rectangle, rect2: &Rectangle) -> f64 {
+    let dx = rect1.x_max.min(rect2.x_max) - rect1.x_min.max(rect2.x_min);
+    let dy = rect1.y_max.min(rect2.y_max) - rect1.y_min.max(rect2.y_min);
+    if dx > 0. && dy > 0. {
+        dx * dy
+    } else {
+        0.
+    }
+}
+
+/// Envelopes of entries[..k] and entries[k..].
+fn split_envelopes(entries: &[(usize, Rectangle)], k: usize) -> (Rectangle, Rectangle) {
+    let envelope_of = |entries: &[(usize, Rectangle)]| {
+        entries
+            .iter()
+            .fold(Rectangle::new_empty(), |envelope, (_, rect)| {
+                envelope.merge(rect)
+            })
+    };
+    (envelope_of(&entries[..k]), envelope_of(&entries[k..]))
+}
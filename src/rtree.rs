@@ -1,9 +1,633 @@
-use crate::Rectangle;
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::ops::ControlFlow;
 
-pub trait RTree {
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+
+#[cfg(feature = "parallel")]
+use crate::utils::divup;
+use crate::utils::total_cmp;
+use crate::{Coordinate, Hilbert, Rectangle};
+
+pub trait RTree: DynQuery {
     fn is_empty(&self) -> bool;
     fn height(&self) -> usize;
     fn degree(&self) -> usize;
     fn envelope(&self) -> Rectangle;
-    fn query_rect(&self, rect: &Rectangle) -> Vec<usize>;
+
+    /**
+     * Call visitor on each geometry that might intersect rect, reusing
+     * scratch for the traversal.
+     *
+     * Returning ControlFlow::Break from visitor stops the query early, and is
+     * passed back to the caller.
+     */
+    fn query_rect_with_scratch<F>(
+        &self,
+        rect: &Rectangle,
+        scratch: &mut QueryScratch,
+        visitor: F,
+    ) -> ControlFlow<()>
+    where
+        Self: Sized,
+        F: FnMut(usize) -> ControlFlow<()>;
+
+    /// Like query_rect_with_scratch, but with a fresh traversal stack.
+    fn query_rect_with<F>(&self, rect: &Rectangle, visitor: F) -> ControlFlow<()>
+    where
+        Self: Sized,
+        F: FnMut(usize) -> ControlFlow<()>,
+    {
+        self.query_rect_with_scratch(rect, &mut QueryScratch::new(), visitor)
+    }
+
+    /**
+     * Call visitor on each geometry whose envelope might contain point.
+     *
+     * This is a query with a degenerate rectangle, but trees can specialize
+     * it to skip checks that can't succeed for a point.  Of the trees here,
+     * only PackedRTreeUnsorted (and the sorted trees built on it) does, since
+     * its rect queries also check whether nodes are contained by the query.
+     */
+    fn query_point_with_scratch<F>(
+        &self,
+        point: Coordinate,
+        scratch: &mut QueryScratch,
+        visitor: F,
+    ) -> ControlFlow<()>
+    where
+        Self: Sized,
+        F: FnMut(usize) -> ControlFlow<()>,
+    {
+        self.query_rect_with_scratch(&Rectangle::new(point, point), scratch, visitor)
+    }
+
+    fn query_point(&self, point: Coordinate) -> Vec<usize>
+    where
+        Self: Sized,
+    {
+        let mut results = Vec::new();
+        let _ = self.query_point_with_scratch(point, &mut QueryScratch::new(), |i| {
+            results.push(i);
+            ControlFlow::Continue(())
+        });
+        results
+    }
+
+    /// Replace the contents of results with the geometries that might intersect rect.
+    fn query_rect_into(
+        &self,
+        rect: &Rectangle,
+        results: &mut Vec<usize>,
+        scratch: &mut QueryScratch,
+    ) where
+        Self: Sized,
+    {
+        results.clear();
+        let _ = self.query_rect_with_scratch(rect, scratch, |i| {
+            results.push(i);
+            ControlFlow::Continue(())
+        });
+    }
+
+    fn query_rect(&self, rect: &Rectangle) -> Vec<usize>
+    where
+        Self: Sized,
+    {
+        let mut results = Vec::new();
+        self.query_rect_into(rect, &mut results, &mut QueryScratch::new());
+        results
+    }
+
+    /**
+     * Lazily iterate over geometries that might intersect rect.
+     *
+     * The tree is only traversed as far as the iterator is advanced.
+     */
+    fn query_rect_iter(&self, rect: &Rectangle) -> QueryIter<'_, Self>
+    where
+        Self: Sized,
+    {
+        QueryIter::new(self, *rect)
+    }
+
+    /// Run query_rect for each of queries, in parallel with the parallel feature.
+    fn query_rect_batch(&self, queries: &[Rectangle]) -> Vec<Vec<usize>>
+    where
+        Self: Sized + Sync,
+    {
+        #[cfg(feature = "parallel")]
+        let results = queries
+            .par_iter()
+            .map_init(QueryScratch::new, |scratch, query| {
+                let mut results = Vec::new();
+                self.query_rect_into(query, &mut results, scratch);
+                results
+            })
+            .collect();
+        #[cfg(not(feature = "parallel"))]
+        let results = {
+            let mut scratch = QueryScratch::new();
+            queries
+                .iter()
+                .map(|query| {
+                    let mut results = Vec::new();
+                    self.query_rect_into(query, &mut results, &mut scratch);
+                    results
+                })
+                .collect()
+        };
+        results
+    }
+
+    /**
+     * Like query_rect_batch, but runs the queries in Hilbert order of their
+     * centers, so that consecutive queries touch nearby nodes.
+     *
+     * Results are still returned in the order of queries.
+     */
+    fn query_rect_batch_sorted(&self, queries: &[Rectangle]) -> Vec<Vec<usize>>
+    where
+        Self: Sized + Sync,
+    {
+        let hilbert = Hilbert::new(Rectangle::of(queries));
+        let mut order: Vec<(u32, usize)> = queries
+            .iter()
+            .enumerate()
+            .filter(|(_i, q)| !q.is_empty())
+            .map(|(i, q)| (hilbert.hilbert(q.center()), i))
+            .collect();
+        order.sort_unstable();
+
+        let sorted_queries: Vec<Rectangle> = order.iter().map(|&(_h, i)| queries[i]).collect();
+        let mut results = vec![Vec::new(); queries.len()];
+        for (&(_h, i), result) in order.iter().zip(self.query_rect_batch(&sorted_queries)) {
+            results[i] = result;
+        }
+        results
+    }
+
+    /// Like query_rect_batch, but with all results in a single buffer.
+    fn query_rect_batch_flat(&self, queries: &[Rectangle]) -> BatchResults
+    where
+        Self: Sized + Sync,
+    {
+        let query_chunk = |queries: &[Rectangle], scratch: &mut QueryScratch| {
+            let mut results = BatchResults::with_capacity(queries.len());
+            for query in queries {
+                let _ = self.query_rect_with_scratch(query, scratch, |i| {
+                    results.indices.push(i);
+                    ControlFlow::Continue(())
+                });
+                results.offsets.push(results.indices.len());
+            }
+            results
+        };
+
+        #[cfg(feature = "parallel")]
+        let results = {
+            // Each chunk fills its own buffer, and the buffers are joined.
+            let chunk_size = divup(queries.len(), 4 * rayon::current_num_threads()).max(1);
+            let chunk_results: Vec<BatchResults> = queries
+                .par_chunks(chunk_size)
+                .map_init(QueryScratch::new, |scratch, chunk| {
+                    query_chunk(chunk, scratch)
+                })
+                .collect();
+            BatchResults::concat(chunk_results)
+        };
+        #[cfg(not(feature = "parallel"))]
+        let results = query_chunk(queries, &mut QueryScratch::new());
+        results
+    }
+
+    /**
+     * Find all pairs of geometries that might intersect each other.
+     *
+     * Pairs are returned as (i, j) with i < j.  This descends the tree
+     * against itself, so it only checks bounding-box intersection.
+     */
+    fn query_self_intersections(&self) -> Vec<(usize, usize)> {
+        let mut results = Vec::new();
+        if self.is_empty() {
+            return results;
+        }
+
+        // Both nodes in a stack entry are on the same level, and their
+        // envelopes intersect.
+        let root_offset = self.root_offset();
+        let mut stack = vec![(self.height() - 1, root_offset, root_offset)];
+
+        while let Some((level, offset1, offset2)) = stack.pop() {
+            if level == 0 {
+                // A leaf intersects itself, but that's not interesting.
+                if offset1 != offset2 {
+                    let (i, j) = (self.get_index(offset1), self.get_index(offset2));
+                    results.push((i.min(j), i.max(j)));
+                }
+                continue;
+            }
+
+            for (inc1, child_offset1) in child_offsets(self, level, offset1).enumerate() {
+                let child1 = self.get_rectangle(level - 1, child_offset1);
+                // If we are comparing a node to itself, only check each pair once.
+                let start2 = if offset1 == offset2 { inc1 } else { 0 };
+                for child_offset2 in child_offsets(self, level, offset2).skip(start2) {
+                    if child1.intersects(&self.get_rectangle(level - 1, child_offset2)) {
+                        stack.push((level - 1, child_offset1, child_offset2));
+                    }
+                }
+            }
+        }
+
+        results
+    }
+
+    /// Envelope of the node at offset in level; leaves are level 0.
+    fn get_rectangle(&self, level: usize, offset: usize) -> Rectangle;
+
+    /// Index of the original item stored at leaf offset.
+    fn get_index(&self, offset: usize) -> usize;
+
+    /// Offset of the root, at level height() - 1.
+    fn root_offset(&self) -> usize {
+        0
+    }
+
+    /**
+     * Offset of the child at position of the node at offset in level, or
+     * None if the node has no more children.
+     *
+     * Packed trees store the children of a node at consecutive offsets, some
+     * of which may have empty envelopes.
+     */
+    fn child_offset(&self, _level: usize, offset: usize, position: usize) -> Option<usize> {
+        Some(self.degree() * offset + position)
+    }
+
+    /**
+     * Find the k geometries whose envelopes are closest to point.
+     *
+     * Results are ordered by envelope distance, nearest first.  Nodes are
+     * visited best-first, ordered by the minimum distance to their envelope.
+     */
+    fn nearest(&self, point: Coordinate, k: usize) -> Vec<usize> {
+        let mut results = Vec::with_capacity(k);
+        // The root is the only leaf of a one item tree, so it can be empty.
+        let envelope = self.envelope();
+        if self.is_empty() || k == 0 || envelope.is_empty() {
+            return results;
+        }
+
+        let mut queue = BinaryHeap::new();
+        queue.push(NodeDistance {
+            distance: envelope.distance_squared(point),
+            level: self.height() - 1,
+            offset: self.root_offset(),
+        });
+
+        while let Some(NodeDistance { level, offset, .. }) = queue.pop() {
+            if level == 0 {
+                results.push(self.get_index(offset));
+                if results.len() == k {
+                    break;
+                }
+                continue;
+            }
+
+            for child_offset in child_offsets(self, level, offset) {
+                let rect = self.get_rectangle(level - 1, child_offset);
+                if rect.is_empty() {
+                    continue;
+                }
+                queue.push(NodeDistance {
+                    distance: rect.distance_squared(point),
+                    level: level - 1,
+                    offset: child_offset,
+                });
+            }
+        }
+
+        results
+    }
+
+    /**
+     * Find the geometries whose envelopes are within distance of rect, with
+     * their distances.
+     *
+     * Nodes are pruned by their Euclidean distance to rect, so this doesn't
+     * visit the corners of an expanded query box.  Results are in no
+     * particular order.
+     */
+    fn query_within_distance(&self, rect: &Rectangle, distance: f64) -> Vec<(usize, f64)> {
+        let mut results = Vec::new();
+        if self.is_empty() || rect.is_empty() || distance < 0. {
+            return results;
+        }
+
+        let max_distance_squared = distance * distance;
+        let mut stack = Vec::new();
+        let envelope = self.envelope();
+        if !envelope.is_empty() && rect.distance_squared_to(&envelope) <= max_distance_squared {
+            stack.push((self.height() - 1, self.root_offset()));
+        }
+
+        while let Some((level, offset)) = stack.pop() {
+            if level == 0 {
+                let distance_squared = rect.distance_squared_to(&self.get_rectangle(0, offset));
+                results.push((self.get_index(offset), distance_squared.sqrt()));
+                continue;
+            }
+
+            for child_offset in child_offsets(self, level, offset) {
+                let child = self.get_rectangle(level - 1, child_offset);
+                if !child.is_empty() && rect.distance_squared_to(&child) <= max_distance_squared {
+                    stack.push((level - 1, child_offset));
+                }
+            }
+        }
+
+        results
+    }
+
+    /**
+     * Find all pairs (i, j) of geometries whose envelopes intersect, where i
+     * is an index into this tree and j is an index into other.
+     *
+     * The trees are descended simultaneously, so they can have different
+     * degrees, heights, and implementations.
+     */
+    fn query_rtree_intersections(&self, other: &(impl RTree + ?Sized)) -> Vec<(usize, usize)>
+    where
+        Self: Sized,
+    {
+        let mut results = Vec::new();
+        if self.is_empty() || other.is_empty() {
+            return results;
+        }
+
+        // Stack entries: ((level1, offset1), (level2, offset2)), whose
+        // envelopes intersect.
+        let mut stack = Vec::new();
+        if self.envelope().intersects(&other.envelope()) {
+            stack.push((
+                (self.height() - 1, self.root_offset()),
+                (other.height() - 1, other.root_offset()),
+            ));
+        }
+
+        while let Some(((level1, offset1), (level2, offset2))) = stack.pop() {
+            if level1 == 0 && level2 == 0 {
+                results.push((self.get_index(offset1), other.get_index(offset2)));
+                continue;
+            }
+
+            // Descend the higher node, so the two sides stay comparable in size.
+            if level1 >= level2 {
+                let rect2 = other.get_rectangle(level2, offset2);
+                for child_offset in child_offsets(self, level1, offset1) {
+                    if self
+                        .get_rectangle(level1 - 1, child_offset)
+                        .intersects(&rect2)
+                    {
+                        stack.push(((level1 - 1, child_offset), (level2, offset2)));
+                    }
+                }
+            } else {
+                let rect1 = self.get_rectangle(level1, offset1);
+                for child_offset in child_offsets(other, level2, offset2) {
+                    if other
+                        .get_rectangle(level2 - 1, child_offset)
+                        .intersects(&rect1)
+                    {
+                        stack.push(((level1, offset1), (level2 - 1, child_offset)));
+                    }
+                }
+            }
+        }
+
+        results
+    }
+}
+
+/// Offsets of the children of the node at offset in level, which must be above the leaves.
+fn child_offsets<T: RTree + ?Sized>(
+    rtree: &T,
+    level: usize,
+    offset: usize,
+) -> impl Iterator<Item = usize> + '_ {
+    (0..rtree.degree()).map_while(move |position| rtree.child_offset(level, offset, position))
+}
+
+/**
+ * Queries with a dynamically dispatched visitor, so they can be made through
+ * dyn RTree.
+ *
+ * This is implemented for every RTree, and the generic queries should be
+ * preferred where the type is known.
+ */
+pub trait DynQuery {
+    fn query_rect_dyn(
+        &self,
+        rect: &Rectangle,
+        scratch: &mut QueryScratch,
+        visitor: &mut dyn FnMut(usize) -> ControlFlow<()>,
+    ) -> ControlFlow<()>;
+
+    fn query_point_dyn(
+        &self,
+        point: Coordinate,
+        scratch: &mut QueryScratch,
+        visitor: &mut dyn FnMut(usize) -> ControlFlow<()>,
+    ) -> ControlFlow<()>;
+}
+
+impl<T: RTree> DynQuery for T {
+    fn query_rect_dyn(
+        &self,
+        rect: &Rectangle,
+        scratch: &mut QueryScratch,
+        visitor: &mut dyn FnMut(usize) -> ControlFlow<()>,
+    ) -> ControlFlow<()> {
+        self.query_rect_with_scratch(rect, scratch, visitor)
+    }
+
+    fn query_point_dyn(
+        &self,
+        point: Coordinate,
+        scratch: &mut QueryScratch,
+        visitor: &mut dyn FnMut(usize) -> ControlFlow<()>,
+    ) -> ControlFlow<()> {
+        self.query_point_with_scratch(point, scratch, visitor)
+    }
+}
+
+/// Iterator over the results of RTree::query_rect_iter.
+pub struct QueryIter<'a, T: RTree> {
+    rtree: &'a T,
+    query: Rectangle,
+    // Stack entries: (level, offset, contained).  If contained is true, the
+    // query contains the node, so its children don't need to be checked.
+    stack: Vec<(usize, usize, bool)>,
+}
+
+impl<'a, T: RTree> QueryIter<'a, T> {
+    fn new(rtree: &'a T, query: Rectangle) -> Self {
+        let mut stack = Vec::new();
+        let envelope = rtree.envelope();
+        if !rtree.is_empty() && query.intersects(&envelope) {
+            stack.push((
+                rtree.height() - 1,
+                rtree.root_offset(),
+                query.contains(&envelope),
+            ));
+        }
+        QueryIter {
+            rtree,
+            query,
+            stack,
+        }
+    }
+}
+
+impl<'a, T: RTree> Iterator for QueryIter<'a, T> {
+    type Item = usize;
+
+    fn next(&mut self) -> Option<usize> {
+        while let Some((level, offset, contained)) = self.stack.pop() {
+            if level == 0 {
+                return Some(self.rtree.get_index(offset));
+            }
+
+            let child_level = level - 1;
+            for child_offset in child_offsets(self.rtree, level, offset) {
+                let child = self.rtree.get_rectangle(child_level, child_offset);
+                if contained {
+                    if !child.is_empty() {
+                        self.stack.push((child_level, child_offset, true));
+                    }
+                } else if self.query.intersects(&child) {
+                    let child_contained = self.query.contains(&child);
+                    self.stack
+                        .push((child_level, child_offset, child_contained));
+                }
+            }
+        }
+        None
+    }
+}
+
+/**
+ * Results of a batch of queries, stored contiguously.
+ *
+ * The results for query i are indices[offsets[i]..offsets[i + 1]].
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct BatchResults {
+    pub offsets: Vec<usize>,
+    pub indices: Vec<usize>,
+}
+
+impl BatchResults {
+    fn with_capacity(num_queries: usize) -> Self {
+        let mut offsets = Vec::with_capacity(num_queries + 1);
+        offsets.push(0);
+        BatchResults {
+            offsets,
+            indices: Vec::new(),
+        }
+    }
+
+    /// The number of queries.
+    pub fn len(&self) -> usize {
+        self.offsets.len() - 1
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The results for query i.
+    pub fn get(&self, i: usize) -> &[usize] {
+        &self.indices[self.offsets[i]..self.offsets[i + 1]]
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &[usize]> + '_ {
+        (0..self.len()).map(move |i| self.get(i))
+    }
+
+    /// Join the results of consecutive batches of queries.
+    #[cfg(feature = "parallel")]
+    fn concat(batches: Vec<BatchResults>) -> Self {
+        let num_queries = batches.iter().map(|batch| batch.len()).sum();
+        let mut joined = BatchResults::with_capacity(num_queries);
+        joined
+            .indices
+            .reserve(batches.iter().map(|batch| batch.indices.len()).sum());
+        for batch in batches {
+            let base = joined.indices.len();
+            joined
+                .offsets
+                .extend(batch.offsets[1..].iter().map(|offset| base + offset));
+            joined.indices.extend(batch.indices);
+        }
+        joined
+    }
+}
+
+impl From<Vec<Vec<usize>>> for BatchResults {
+    fn from(results_list: Vec<Vec<usize>>) -> Self {
+        let mut batch = BatchResults::with_capacity(results_list.len());
+        for results in results_list {
+            batch.indices.extend(results);
+            batch.offsets.push(batch.indices.len());
+        }
+        batch
+    }
+}
+
+/// Reusable buffers for queries, so repeated queries don't allocate.
+#[derive(Debug, Default)]
+pub struct QueryScratch {
+    pub(crate) stack: Vec<(usize, usize)>,
+    // For trees that need the envelope of each node on the stack.
+    pub(crate) envelopes: Vec<Rectangle>,
+}
+
+impl QueryScratch {
+    pub fn new() -> Self {
+        QueryScratch {
+            stack: Vec::new(),
+            envelopes: Vec::new(),
+        }
+    }
+}
+
+/// A node in the nearest-neighbor queue, ordered so the closest node is popped first.
+struct NodeDistance {
+    distance: f64,
+    level: usize,
+    offset: usize,
+}
+
+impl PartialEq for NodeDistance {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for NodeDistance {}
+
+impl PartialOrd for NodeDistance {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for NodeDistance {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // BinaryHeap is a max-heap, so reverse the distances.
+        total_cmp(other.distance, self.distance)
+    }
 }
@@ -1,11 +1,32 @@
-use crate::{PackedRTree, PackedRTreeAutoSimd, PackedRTreeUnsorted, RTree, Rectangle};
+use std::ops::ControlFlow;
+
+use crate::utils::divup;
+use crate::{
+    Coordinate, HasEnvelope, Hilbert, PackedRTree, PackedRTreeAutoSimd, PackedRTreeF32,
+    PackedRTreeLsm, PackedRTreeMap, PackedRTreeQuantized, PackedRTreeSimd, PackedRTreeSoa,
+    PackedRTreeSortedAutoSimd, PackedRTreeUnsorted, QueryResult, QueryScratch, RStarTree, RTree,
+    Rectangle, SimdKernel, SpaceFillingCurve,
+};
 
 #[test]
 fn test_empty_rtree() {
     assert_empty_rtree(PackedRTreeUnsorted::new_empty());
     assert_empty_rtree(PackedRTreeAutoSimd::new_empty());
+    assert_empty_rtree(PackedRTreeSimd::new_empty());
+    assert_empty_rtree(PackedRTreeSoa::new_empty());
+    assert_empty_rtree(RStarTree::new_empty());
+    assert_empty_rtree(PackedRTreeLsm::new_empty());
+    assert_empty_rtree(PackedRTreeF32::new_empty());
+    assert_empty_rtree(PackedRTreeQuantized::new_empty());
     assert_empty_rtree(PackedRTree::new_hilbert(2, &Vec::<Rectangle>::new()));
-    assert_empty_rtree(PackedRTree::new_omt(&Vec::<Rectangle>::new()));
+    assert_empty_rtree(PackedRTree::new_hilbert64(2, &Vec::<Rectangle>::new()));
+    assert_empty_rtree(PackedRTree::new_morton(2, &Vec::<Rectangle>::new()));
+    assert_empty_rtree(PackedRTree::new_omt(2, &Vec::<Rectangle>::new()));
+    assert_empty_rtree(PackedRTree::new_str(2, &Vec::<Rectangle>::new()));
+    assert_empty_rtree(PackedRTreeSortedAutoSimd::new_hilbert(
+        2,
+        &Vec::<Rectangle>::new(),
+    ));
 }
 
 fn assert_empty_rtree(tree: impl RTree) {
@@ -19,6 +40,12 @@ fn assert_empty_rtree(tree: impl RTree) {
     assert!(tree.envelope().is_empty());
     assert_eq!(tree.height(), 0);
     assert_eq!(tree.query_rect(&r), Vec::<usize>::new());
+    assert_eq!(tree.query_rect_iter(&r).next(), None);
+    assert_eq!(
+        tree.query_self_intersections(),
+        Vec::<(usize, usize)>::new()
+    );
+    assert_eq!(tree.nearest((0., 0.).into(), 3), Vec::<usize>::new());
 }
 
 fn _assert_queries(max_index: usize, tree: &PackedRTreeAutoSimd, rects: &[Rectangle]) {
@@ -32,8 +59,19 @@ fn _assert_queries(max_index: usize, tree: &PackedRTreeAutoSimd, rects: &[Rectan
 fn test_build_tree() {
     assert_build_tree(|envs| PackedRTreeUnsorted::new(16, envs));
     assert_build_tree(|envs| PackedRTreeAutoSimd::new(16, &envs));
+    assert_build_tree(|envs| PackedRTreeSoa::new(16, &envs));
+    assert_build_tree(|envs| RStarTree::new(16, &envs));
+    assert_build_tree(|envs| RStarTree::new(2, &envs));
+    assert_build_tree(|envs| PackedRTreeLsm::new(16, &envs));
+    assert_build_tree(|envs| PackedRTreeF32::new(16, envs));
+    assert_build_tree(|envs| PackedRTreeQuantized::new(16, envs));
     assert_build_tree(|envs| PackedRTree::new_hilbert(16, &envs));
-    assert_build_tree(|envs| PackedRTree::new_omt(&envs));
+    assert_build_tree(|envs| PackedRTree::new_hilbert64(16, &envs));
+    assert_build_tree(|envs| PackedRTree::new_morton(16, &envs));
+    assert_build_tree(|envs| PackedRTree::new_omt(16, &envs));
+    assert_build_tree(|envs| PackedRTree::new_str(16, &envs));
+    assert_build_tree(|envs| PackedRTreeSortedAutoSimd::new_hilbert(16, &envs));
+    assert_build_tree(|envs| PackedRTreeSortedAutoSimd::new_omt(16, &envs));
 }
 
 fn assert_build_tree<R, C>(constructor: C)
@@ -172,6 +210,123 @@ fn get_envelopes() -> Vec<Rectangle> {
         .collect()
 }
 
+/// A curve that orders by x, to check that custom curves can be plugged in.
+struct XCurve;
+
+impl SpaceFillingCurve for XCurve {
+    type Key = i64;
+
+    fn from_envelope(_envelope: Rectangle) -> Self {
+        XCurve
+    }
+
+    fn key(&self, position: Coordinate) -> i64 {
+        position.x as i64
+    }
+}
+
+#[test]
+fn test_custom_curve() {
+    let envelopes = get_envelopes();
+    let query_rect = Rectangle::new((40., 40.).into(), (60., 60.).into());
+    let brute_results = find_brute_intersections(&query_rect, &envelopes);
+
+    let rtree = PackedRTree::new_sorted::<XCurve>(16, &envelopes);
+    let results = get_rtree_intersections(query_rect, rtree);
+    assert_eq!(results, brute_results);
+}
+
+#[test]
+fn test_sorted_build_is_deterministic() {
+    // Parallel builds must match this serial description exactly.
+    let mut envelopes = get_envelopes();
+    envelopes.extend(get_envelopes());
+    let rtree = PackedRTree::new_hilbert(4, &envelopes);
+
+    let hilbert = Hilbert::new(Rectangle::of(&envelopes));
+    let mut expected: Vec<(u32, usize)> = envelopes
+        .iter()
+        .enumerate()
+        .map(|(i, e)| (hilbert.hilbert(e.center()), i))
+        .collect();
+    expected.sort_unstable();
+    let indices: Vec<usize> = (0..envelopes.len()).map(|o| rtree.get_index(o)).collect();
+    assert_eq!(
+        indices,
+        expected.iter().map(|&(_h, i)| i).collect::<Vec<_>>()
+    );
+
+    for level in 1..rtree.height() {
+        let level_size = divup(envelopes.len(), rtree.degree().pow(level as u32));
+        for offset in 0..level_size {
+            let children: Vec<Rectangle> = (0..rtree.degree())
+                .map(|inc| rtree.get_rectangle(level - 1, rtree.degree() * offset + inc))
+                .collect();
+            assert_eq!(rtree.get_rectangle(level, offset), Rectangle::of(&children));
+        }
+    }
+}
+
+#[cfg(feature = "parallel")]
+#[test]
+fn test_parallel_builds_match_serial() {
+    // Spread copies of the envelopes out, so there is work to split.
+    let envelopes: Vec<Rectangle> = (0..20)
+        .flat_map(|i| {
+            let (dx, dy) = (100. * (i % 5) as f64, 100. * (i / 5) as f64);
+            get_envelopes().into_iter().map(move |e| Rectangle {
+                x_min: e.x_min + dx,
+                y_min: e.y_min + dy,
+                x_max: e.x_max + dx,
+                y_max: e.y_max + dy,
+            })
+        })
+        .collect();
+    let serial_pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(1)
+        .build()
+        .unwrap();
+    let parallel_pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(4)
+        .build()
+        .unwrap();
+
+    let builders: Vec<fn(&[Rectangle]) -> PackedRTree> = vec![
+        |envelopes| PackedRTree::new_hilbert(4, envelopes),
+        |envelopes| PackedRTree::new_str(4, envelopes),
+        |envelopes| PackedRTree::new_omt(4, envelopes),
+        |envelopes| PackedRTree::new_omt(3, envelopes),
+    ];
+    for build in builders {
+        let serial = serial_pool.install(|| build(&envelopes));
+        let parallel = parallel_pool.install(|| build(&envelopes));
+        assert_eq!(serial.height(), parallel.height());
+        for offset in 0..envelopes.len() {
+            assert_eq!(serial.get_index(offset), parallel.get_index(offset));
+        }
+        for level in 0..serial.height() {
+            let level_size = divup(envelopes.len(), serial.degree().pow(level as u32));
+            for offset in 0..level_size {
+                assert_eq!(
+                    serial.get_rectangle(level, offset),
+                    parallel.get_rectangle(level, offset)
+                );
+            }
+        }
+    }
+}
+
+#[test]
+fn test_omt_leaves_are_packed() {
+    let envelopes = get_envelopes();
+    for &degree in [2, 3, 4, 16].iter() {
+        let rtree = PackedRTree::new_omt(degree, &envelopes);
+        let mut indices: Vec<usize> = (0..envelopes.len()).map(|o| rtree.get_index(o)).collect();
+        indices.sort_unstable();
+        assert_eq!(indices, (0..envelopes.len()).collect::<Vec<_>>());
+    }
+}
+
 fn get_rtree_intersections(query_rect: Rectangle, rtree: impl RTree) -> Vec<usize> {
     let mut rtree_results = rtree.query_rect(&query_rect);
     rtree_results.sort_unstable();
@@ -191,47 +346,786 @@ fn test_intersection_candidates() {
     let results = get_rtree_intersections(query_rect, PackedRTreeAutoSimd::new(16, &envelopes));
     assert_eq!(results, brute_results);
 
+    let results = get_rtree_intersections(query_rect, PackedRTreeSoa::new(16, &envelopes));
+    assert_eq!(results, brute_results);
+
+    let results = get_rtree_intersections(query_rect, PackedRTreeSoa::new(3, &envelopes));
+    assert_eq!(results, brute_results);
+
+    let results = get_rtree_intersections(query_rect, RStarTree::new(16, &envelopes));
+    assert_eq!(results, brute_results);
+
+    let results = get_rtree_intersections(query_rect, RStarTree::new(3, &envelopes));
+    assert_eq!(results, brute_results);
+
+    let results = get_rtree_intersections(query_rect, PackedRTreeLsm::new(16, &envelopes));
+    assert_eq!(results, brute_results);
+
+    let results = get_rtree_intersections(query_rect, PackedRTreeF32::new(3, envelopes.clone()));
+    assert_eq!(results, brute_results);
+
+    let results =
+        get_rtree_intersections(query_rect, PackedRTreeQuantized::new(3, envelopes.clone()));
+    assert_eq!(results, brute_results);
+
     let results = get_rtree_intersections(query_rect, PackedRTree::new_hilbert(16, &envelopes));
     assert_eq!(results, brute_results);
 
-    let results = get_rtree_intersections(query_rect, PackedRTree::new_omt(&envelopes));
+    let results = get_rtree_intersections(query_rect, PackedRTree::new_hilbert64(16, &envelopes));
+    assert_eq!(results, brute_results);
+
+    let results = get_rtree_intersections(query_rect, PackedRTree::new_morton(16, &envelopes));
+    assert_eq!(results, brute_results);
+
+    let results = get_rtree_intersections(query_rect, PackedRTree::new_omt(16, &envelopes));
+    assert_eq!(results, brute_results);
+
+    let results = get_rtree_intersections(query_rect, PackedRTree::new_omt(3, &envelopes));
+    assert_eq!(results, brute_results);
+
+    let results = get_rtree_intersections(query_rect, PackedRTree::new_str(16, &envelopes));
+    assert_eq!(results, brute_results);
+
+    let results = get_rtree_intersections(query_rect, PackedRTree::new_str(3, &envelopes));
+    assert_eq!(results, brute_results);
+
+    let results = get_rtree_intersections(
+        query_rect,
+        PackedRTreeSortedAutoSimd::new_hilbert(16, &envelopes),
+    );
+    assert_eq!(results, brute_results);
+
+    let results = get_rtree_intersections(
+        query_rect,
+        PackedRTreeSortedAutoSimd::new_omt(3, &envelopes),
+    );
     assert_eq!(results, brute_results);
 }
 
-// #[test]
-// fn test_self_intersection_unsorted() {
-//     let envelopes: Vec<Rectangle> = get_envelopes();
-//     let f = Flatbush::new_unsorted(16, &envelopes);
+fn assert_query_visitors(rtree: impl RTree, envelopes: &[Rectangle]) {
+    let query_rect = Rectangle::new((40., 40.).into(), (60., 60.).into());
+    let brute_results = find_brute_intersections(&query_rect, envelopes);
 
-//     let brute_results = find_brute_self_intersections(&envelopes);
-//     let mut rtree_results = f.query_self_intersections();
-//     rtree_results.sort();
-//     assert_eq!(rtree_results, brute_results);
-// }
+    let mut scratch = QueryScratch::new();
+    let mut results = vec![usize::MAX];
+    rtree.query_rect_into(&query_rect, &mut results, &mut scratch);
+    results.sort_unstable();
+    assert_eq!(results, brute_results);
 
-// #[test]
-// fn test_rtree_intersection_unsorted() {
-//     let mut envelopes1 = get_envelopes();
-//     let n_envs = envelopes1.len();
-//     let envelopes2 = envelopes1.split_off(2 * envelopes1.len() / 3);
-//     assert_eq!(envelopes1.len() + envelopes2.len(), n_envs);
+    // Reusing the buffers gives the same answer.
+    rtree.query_rect_into(&query_rect, &mut results, &mut scratch);
+    results.sort_unstable();
+    assert_eq!(results, brute_results);
 
-//     let f1 = Flatbush::new_unsorted(&envelopes1, 16);
-//     let f2 = Flatbush::new_unsorted(&envelopes2, 16);
-//     let mut rtree_results = f1.find_other_rtree_intersection_candidates(&f2);
-//     rtree_results.sort();
-//     let brute_results = find_brute_cross_intersections(&envelopes1, &envelopes2);
-//     assert_eq!(rtree_results, brute_results);
-// }
+    let mut visited = Vec::new();
+    let flow = rtree.query_rect_with(&query_rect, |i| {
+        visited.push(i);
+        if visited.len() == 2 {
+            ControlFlow::Break(())
+        } else {
+            ControlFlow::Continue(())
+        }
+    });
+    assert_eq!(flow, ControlFlow::Break(()));
+    assert_eq!(visited.len(), 2);
+    assert!(visited.iter().all(|i| brute_results.contains(i)));
+}
 
-// #[test]
-// fn test_rtree_intersection_with_empty() {
-//     let envelopes1 = get_envelopes();
-//     let f1 = Flatbush::new(&envelopes1, 16);
-//     let f2 = Flatbush::new_empty();
-//     let rtree_results = f1.find_other_rtree_intersection_candidates(&f2);
-//     assert_eq!(rtree_results, vec![]);
-// }
+fn assert_query_iter(rtree: impl RTree, envelopes: &[Rectangle]) {
+    let query_rect = Rectangle::new((40., 40.).into(), (60., 60.).into());
+    let brute_results = find_brute_intersections(&query_rect, envelopes);
+    let mut results: Vec<usize> = rtree.query_rect_iter(&query_rect).collect();
+    results.sort_unstable();
+    assert_eq!(results, brute_results);
+
+    // A query containing everything returns everything.
+    let mut results: Vec<usize> = rtree.query_rect_iter(&rtree.envelope()).collect();
+    results.sort_unstable();
+    assert_eq!(results, (0..envelopes.len()).collect::<Vec<_>>());
+
+    let first = rtree.query_rect_iter(&query_rect).next().unwrap();
+    assert!(brute_results.contains(&first));
+}
+
+#[test]
+fn test_query_iter() {
+    let envelopes = get_envelopes();
+    assert_query_iter(PackedRTreeUnsorted::new(16, envelopes.clone()), &envelopes);
+    assert_query_iter(PackedRTreeAutoSimd::new(16, &envelopes), &envelopes);
+    assert_query_iter(PackedRTreeSoa::new(16, &envelopes), &envelopes);
+    assert_query_iter(RStarTree::new(4, &envelopes), &envelopes);
+    assert_query_iter(PackedRTreeQuantized::new(4, envelopes.clone()), &envelopes);
+    assert_query_iter(PackedRTree::new_hilbert(16, &envelopes), &envelopes);
+    assert_query_iter(PackedRTree::new_omt(16, &envelopes), &envelopes);
+    assert_query_iter(PackedRTree::new_str(16, &envelopes), &envelopes);
+    assert_query_iter(
+        PackedRTreeMap::new_hilbert(4, envelopes.clone()),
+        &envelopes,
+    );
+    assert_query_iter(
+        PackedRTreeSortedAutoSimd::new_omt(4, &envelopes),
+        &envelopes,
+    );
+}
+
+#[test]
+fn test_query_visitors() {
+    let envelopes = get_envelopes();
+    assert_query_visitors(PackedRTreeUnsorted::new(16, envelopes.clone()), &envelopes);
+    assert_query_visitors(PackedRTreeAutoSimd::new(16, &envelopes), &envelopes);
+    assert_query_visitors(RStarTree::new(4, &envelopes), &envelopes);
+    assert_query_visitors(PackedRTreeF32::new(4, envelopes.clone()), &envelopes);
+    assert_query_visitors(PackedRTreeQuantized::new(4, envelopes.clone()), &envelopes);
+    assert_query_visitors(PackedRTree::new_hilbert(16, &envelopes), &envelopes);
+    assert_query_visitors(PackedRTree::new_omt(16, &envelopes), &envelopes);
+    assert_query_visitors(PackedRTree::new_str(16, &envelopes), &envelopes);
+    assert_query_visitors(
+        PackedRTreeSortedAutoSimd::new_hilbert(4, &envelopes),
+        &envelopes,
+    );
+}
+
+fn assert_query_point(rtree: impl RTree, envelopes: &[Rectangle]) {
+    let points = envelopes
+        .iter()
+        .flat_map(|e| vec![e.center(), Coordinate::new(e.x_min, e.y_max)])
+        .chain(vec![Coordinate::new(45., 45.), Coordinate::new(-1., 200.)]);
+    for point in points {
+        let mut results = rtree.query_point(point);
+        results.sort_unstable();
+        let point_rect = Rectangle::new(point, point);
+        assert_eq!(results, find_brute_intersections(&point_rect, envelopes));
+    }
+}
+
+#[test]
+fn test_query_point() {
+    let envelopes = get_envelopes();
+    assert_query_point(PackedRTreeUnsorted::new(4, envelopes.clone()), &envelopes);
+    assert_query_point(PackedRTreeAutoSimd::new(4, &envelopes), &envelopes);
+    assert_query_point(
+        PackedRTreeSortedAutoSimd::new_hilbert(4, &envelopes),
+        &envelopes,
+    );
+    for kernel in SimdKernel::available() {
+        for &degree in [2, 5, 16].iter() {
+            assert_query_point(
+                PackedRTreeSimd::new_with_kernel(degree, &envelopes, kernel),
+                &envelopes,
+            );
+        }
+    }
+    assert_query_point(PackedRTreeSoa::new(4, &envelopes), &envelopes);
+    assert_query_point(PackedRTreeF32::new(4, envelopes.clone()), &envelopes);
+    assert_query_point(PackedRTreeQuantized::new(4, envelopes.clone()), &envelopes);
+    assert_query_point(PackedRTree::new_hilbert(4, &envelopes), &envelopes);
+    assert_query_point(PackedRTree::new_str(16, &envelopes), &envelopes);
+    assert_query_point(PackedRTreeMap::new_omt(4, envelopes.clone()), &envelopes);
+    assert_query_point(PackedRTreeLsm::new(4, &envelopes), &envelopes);
+    assert_query_point(RStarTree::new(4, &envelopes), &envelopes);
+}
+
+fn assert_within_distance(rtree: impl RTree, envelopes: &[Rectangle]) {
+    let query_rect = Rectangle::new((40., 40.).into(), (45., 50.).into());
+    let distance = 10.;
+    let mut results = rtree.query_within_distance(&query_rect, distance);
+    results.sort_unstable_by_key(|&(i, _d)| i);
+
+    let brute_results: Vec<(usize, f64)> = envelopes
+        .iter()
+        .enumerate()
+        .map(|(i, e)| (i, query_rect.distance_squared_to(e).sqrt()))
+        .filter(|&(_i, d)| d <= distance)
+        .collect();
+    assert_eq!(results, brute_results);
+    // The expanded query box would include candidates in its corners.
+    let expanded = Rectangle::new((30., 30.).into(), (55., 60.).into());
+    assert!(find_brute_intersections(&expanded, envelopes).len() > results.len());
+}
+
+#[test]
+fn test_within_distance() {
+    let envelopes = get_envelopes();
+    assert_within_distance(PackedRTreeUnsorted::new(4, envelopes.clone()), &envelopes);
+    assert_within_distance(PackedRTreeAutoSimd::new(4, &envelopes), &envelopes);
+    assert_within_distance(PackedRTreeSoa::new(4, &envelopes), &envelopes);
+    assert_within_distance(PackedRTreeF32::new(4, envelopes.clone()), &envelopes);
+    assert_within_distance(PackedRTree::new_hilbert(4, &envelopes), &envelopes);
+    assert_within_distance(PackedRTree::new_omt(16, &envelopes), &envelopes);
+    assert_within_distance(PackedRTreeLsm::new(4, &envelopes), &envelopes);
+    assert_within_distance(RStarTree::new(4, &envelopes), &envelopes);
+}
+
+fn assert_query_batch(rtree: impl RTree + Sync) {
+    let queries: Vec<Rectangle> = get_envelopes()
+        .iter()
+        .map(|e| Rectangle::new(e.center(), e.center() + Coordinate::new(10., 10.)))
+        .chain(vec![Rectangle::new_empty()])
+        .collect();
+    let sort_each = |mut results_list: Vec<Vec<usize>>| {
+        results_list.iter_mut().for_each(|r| r.sort_unstable());
+        results_list
+    };
+    let expected = sort_each(queries.iter().map(|q| rtree.query_rect(q)).collect());
+    assert_eq!(sort_each(rtree.query_rect_batch(&queries)), expected);
+    assert_eq!(sort_each(rtree.query_rect_batch_sorted(&queries)), expected);
+
+    let flat = rtree.query_rect_batch_flat(&queries);
+    assert_eq!(flat.len(), queries.len());
+    assert_eq!(
+        sort_each(flat.iter().map(|r| r.to_vec()).collect()),
+        expected
+    );
+}
+
+#[test]
+fn test_query_batch() {
+    let envelopes = get_envelopes();
+    assert_query_batch(PackedRTreeUnsorted::new(16, envelopes.clone()));
+    assert_query_batch(PackedRTreeAutoSimd::new(16, &envelopes));
+    assert_query_batch(PackedRTreeQuantized::new(4, envelopes.clone()));
+    assert_query_batch(PackedRTree::new_hilbert(16, &envelopes));
+}
+
+fn assert_nearest(rtree: impl RTree, envelopes: &[Rectangle]) {
+    let point = Coordinate::new(45., 45.);
+    let k = 10;
+    let brute_distances = find_brute_nearest_distances(point, k, envelopes);
+    let results = rtree.nearest(point, k);
+    let distances: Vec<f64> = results
+        .iter()
+        .map(|&i| envelopes[i].distance_squared(point))
+        .collect();
+    // Ties may be broken in any order, so compare distances instead of indices.
+    assert_eq!(distances, brute_distances);
+}
+
+#[test]
+fn test_nearest() {
+    let envelopes = get_envelopes();
+    assert_nearest(PackedRTreeUnsorted::new(16, envelopes.clone()), &envelopes);
+    assert_nearest(PackedRTreeAutoSimd::new(16, &envelopes), &envelopes);
+    assert_nearest(PackedRTreeSoa::new(16, &envelopes), &envelopes);
+    assert_nearest(RStarTree::new(4, &envelopes), &envelopes);
+    assert_nearest(PackedRTreeF32::new(4, envelopes.clone()), &envelopes);
+    assert_nearest(PackedRTreeQuantized::new(4, envelopes.clone()), &envelopes);
+    assert_nearest(PackedRTree::new_hilbert(16, &envelopes), &envelopes);
+    assert_nearest(PackedRTree::new_omt(16, &envelopes), &envelopes);
+    assert_nearest(PackedRTree::new_str(16, &envelopes), &envelopes);
+    assert_nearest(
+        PackedRTreeSortedAutoSimd::new_hilbert(4, &envelopes),
+        &envelopes,
+    );
+}
+
+#[test]
+fn test_nearest_more_than_size() {
+    let envelopes = get_envelopes();
+    let results = PackedRTree::new_omt(16, &envelopes).nearest((0., 0.).into(), 1000);
+    assert_eq!(results.len(), envelopes.len());
+}
+
+#[test]
+fn test_dyn_rtree() {
+    let envelopes = get_envelopes();
+    let rtrees: Vec<Box<dyn RTree>> = vec![
+        Box::new(PackedRTreeUnsorted::new(4, envelopes.clone())),
+        Box::new(PackedRTreeAutoSimd::new(16, &envelopes)),
+        Box::new(PackedRTree::new_hilbert(4, &envelopes)),
+        Box::new(RStarTree::new(4, &envelopes)),
+    ];
+    let query_rect = Rectangle::new((40., 40.).into(), (60., 60.).into());
+    let point = Coordinate::new(45., 45.);
+    let brute_cross = find_brute_cross_intersections(&envelopes, &envelopes);
+    let unsorted = PackedRTreeUnsorted::new(16, envelopes.clone());
+    for rtree in rtrees {
+        let mut results = unsorted.query_rtree_intersections(rtree.as_ref());
+        results.sort_unstable();
+        assert_eq!(results, brute_cross);
+
+        let mut scratch = QueryScratch::new();
+        let mut results = Vec::new();
+        let _ = rtree.query_rect_dyn(&query_rect, &mut scratch, &mut |i| {
+            results.push(i);
+            ControlFlow::Continue(())
+        });
+        results.sort_unstable();
+        assert_eq!(results, find_brute_intersections(&query_rect, &envelopes));
+
+        let mut results = Vec::new();
+        let _ = rtree.query_point_dyn(point, &mut scratch, &mut |i| {
+            results.push(i);
+            ControlFlow::Continue(())
+        });
+        results.sort_unstable();
+        let point_rect = Rectangle::new(point, point);
+        assert_eq!(results, find_brute_intersections(&point_rect, &envelopes));
+        assert_eq!(rtree.nearest(point, 3).len(), 3);
+    }
+}
+
+#[test]
+fn test_query_rect_ranges() {
+    let envelopes = get_envelopes();
+    let rtree = PackedRTreeUnsorted::new(4, envelopes.clone());
+
+    let query_rect = Rectangle::new((40., 40.).into(), (60., 60.).into());
+    let mut results: Vec<usize> = rtree
+        .query_rect_ranges(&query_rect)
+        .into_iter()
+        .flatten()
+        .collect();
+    results.sort_unstable();
+    assert_eq!(results, find_brute_intersections(&query_rect, &envelopes));
+
+    let results = rtree.query_rect_ranges(&rtree.envelope());
+    assert_eq!(results, vec![QueryResult::Range(0..envelopes.len())]);
+}
+
+#[test]
+fn test_simd_kernels() {
+    let envelopes = get_envelopes();
+    let query_rect = Rectangle::new((40., 40.).into(), (60., 60.).into());
+    let brute_results = find_brute_intersections(&query_rect, &envelopes);
+
+    for kernel in SimdKernel::available() {
+        for &degree in [2, 5, 16].iter() {
+            let rtree = PackedRTreeSimd::new_with_kernel(degree, &envelopes, kernel);
+            assert_eq!(rtree.kernel(), kernel);
+            let results = get_rtree_intersections(query_rect, rtree);
+            assert_eq!(results, brute_results);
+        }
+    }
+}
+
+#[test]
+fn test_compact_envelopes_contain_children() {
+    let envelopes = get_envelopes();
+    let exact = PackedRTreeUnsorted::new(3, envelopes.clone());
+    let f32_tree = PackedRTreeF32::new(3, envelopes.clone());
+    let quantized = PackedRTreeQuantized::new(3, envelopes.clone());
+    assert_eq!(f32_tree.height(), exact.height());
+    assert_eq!(quantized.height(), exact.height());
+    for level in 0..exact.height() {
+        let level_size = divup(envelopes.len(), 3usize.pow(level as u32));
+        for offset in 0..level_size {
+            let rect = exact.get_rectangle(level, offset);
+            assert!(f32_tree.get_rectangle(level, offset).contains(&rect));
+            assert!(quantized.get_rectangle(level, offset).contains(&rect));
+        }
+    }
+}
+
+struct Named {
+    name: String,
+    envelope: Rectangle,
+}
+
+impl HasEnvelope for Named {
+    fn envelope(&self) -> Rectangle {
+        self.envelope
+    }
+}
+
+#[test]
+fn test_rtree_map() {
+    let envelopes = get_envelopes();
+    let items: Vec<Named> = envelopes
+        .iter()
+        .enumerate()
+        .map(|(i, &envelope)| Named {
+            name: i.to_string(),
+            envelope,
+        })
+        .collect();
+    let query_rect = Rectangle::new((40., 40.).into(), (60., 60.).into());
+    let brute_results = find_brute_intersections(&query_rect, &envelopes);
+
+    let map = PackedRTreeMap::new_omt(4, items);
+    assert_eq!(map.items().len(), envelopes.len());
+    let mut indices = map.query_rect(&query_rect);
+    indices.sort_unstable();
+    assert_eq!(indices, brute_results);
+    let mut names: Vec<usize> = map
+        .query_rect_items(&query_rect)
+        .iter()
+        .map(|item| item.name.parse().unwrap())
+        .collect();
+    names.sort_unstable();
+    assert_eq!(names, brute_results);
+
+    let items = map.into_inner();
+    for (i, item) in items.iter().enumerate() {
+        assert_eq!(item.name, i.to_string());
+    }
+}
+
+#[test]
+fn test_rstar_tree_remove() {
+    let envelopes = get_envelopes();
+    let mut rtree = RStarTree::new(4, &envelopes);
+    let query_rect = Rectangle::new((20., 20.).into(), (80., 80.).into());
+
+    // Remove every other item, then check queries against the rest.
+    let mut remaining = Vec::new();
+    for (i, envelope) in envelopes.iter().enumerate() {
+        if i % 2 == 0 {
+            assert!(rtree.remove(i));
+            assert!(!rtree.remove(i));
+        } else {
+            remaining.push(*envelope);
+        }
+    }
+    let mut results: Vec<usize> = rtree
+        .query_rect(&query_rect)
+        .into_iter()
+        .map(|i| i / 2)
+        .collect();
+    results.sort_unstable();
+    assert_eq!(results, find_brute_intersections(&query_rect, &remaining));
+
+    // Reinserting gives new indices.
+    let index = rtree.insert(&envelopes[0]);
+    assert_eq!(index, envelopes.len());
+    assert!(rtree.query_rect(&envelopes[0]).contains(&index));
+
+    for i in 0..=envelopes.len() {
+        rtree.remove(i);
+    }
+    assert!(rtree.is_empty());
+    assert!(rtree.query_rect(&query_rect).is_empty());
+}
+
+#[test]
+fn test_rstar_tree_min_fill() {
+    let envelopes = get_envelopes();
+    // Small degrees are raised, so nodes below the root have 2 children.
+    for &degree in [2, 3, 4, 5].iter() {
+        let mut rtree = RStarTree::new(degree, &envelopes);
+        for i in (0..envelopes.len()).step_by(3) {
+            rtree.remove(i);
+        }
+        assert!(rtree.degree() >= 4);
+
+        let mut stack = vec![(rtree.height() - 1, rtree.root_offset())];
+        while let Some((level, offset)) = stack.pop() {
+            if level == 0 {
+                continue;
+            }
+            let children: Vec<(usize, usize)> = (0..rtree.degree())
+                .map_while(|position| rtree.child_offset(level, offset, position))
+                .map(|child_offset| (level - 1, child_offset))
+                .collect();
+            if level + 1 < rtree.height() {
+                assert!(children.len() >= 2);
+            }
+            stack.extend(children);
+        }
+    }
+}
+
+fn get_lsm_in_batches(degree: usize, envelopes: &[Rectangle]) -> PackedRTreeLsm {
+    let mut lsm = PackedRTreeLsm::with_degree(degree);
+    for (i, batch) in envelopes.chunks(3).enumerate() {
+        assert_eq!(lsm.append(batch), (3 * i)..(3 * i + batch.len()));
+    }
+    lsm.finish_merges();
+    lsm
+}
+
+#[test]
+fn test_lsm_append() {
+    let envelopes = get_envelopes();
+    let query_rect = Rectangle::new((40., 40.).into(), (60., 60.).into());
+    let brute_results = find_brute_intersections(&query_rect, &envelopes);
+
+    for &degree in [2, 4, 16].iter() {
+        let lsm = || get_lsm_in_batches(degree, &envelopes);
+        let num_batches = divup(envelopes.len(), 3);
+        assert!(lsm().num_runs() <= (num_batches as f64).log2().ceil() as usize + 1);
+        assert_eq!(get_rtree_intersections(query_rect, lsm()), brute_results);
+        assert_query_iter(lsm(), &envelopes);
+        assert_nearest(lsm(), &envelopes);
+        assert_eq!(
+            get_rtree_self_intersections(lsm()),
+            find_brute_self_intersections(&envelopes)
+        );
+    }
+}
+
+#[test]
+fn test_lsm_queries_during_merges() {
+    let envelopes = get_envelopes();
+    let query_rect = Rectangle::new((40., 40.).into(), (60., 60.).into());
+    let mut lsm = PackedRTreeLsm::with_degree(4);
+    for (i, batch) in envelopes.chunks(3).enumerate() {
+        lsm.append(batch);
+        // Items are found before the merges they are part of finish.
+        let appended = &envelopes[..(3 * i + batch.len())];
+        let mut results = lsm.query_rect(&query_rect);
+        results.sort_unstable();
+        assert_eq!(results, find_brute_intersections(&query_rect, appended));
+    }
+}
+
+#[test]
+fn test_lsm_runs_are_bounded() {
+    let envelopes = get_envelopes();
+    let mut lsm = PackedRTreeLsm::with_degree(4);
+    for _ in 0..20 {
+        for envelope in &envelopes {
+            lsm.append(&[*envelope]);
+            // Merges may still be running, but only a bounded number of
+            // runs wait behind them.
+            assert!(lsm.num_runs() <= 40);
+        }
+    }
+    lsm.finish_merges();
+    let query_rect = Rectangle::new((40., 40.).into(), (60., 60.).into());
+    let results: Vec<usize> = get_rtree_intersections(query_rect, lsm)
+        .into_iter()
+        .filter(|&i| i < envelopes.len())
+        .collect();
+    assert_eq!(results, find_brute_intersections(&query_rect, &envelopes));
+}
+
+#[test]
+fn test_remove() {
+    let envelopes = get_envelopes();
+    let query_rect = Rectangle::new((20., 20.).into(), (80., 80.).into());
+    let removed = |i: usize| i % 3 == 1;
+    let remaining: Vec<Rectangle> = envelopes
+        .iter()
+        .enumerate()
+        .map(|(i, e)| {
+            if removed(i) {
+                Rectangle::new_empty()
+            } else {
+                *e
+            }
+        })
+        .collect();
+    let brute_results = find_brute_intersections(&query_rect, &remaining);
+
+    let mut unsorted = PackedRTreeUnsorted::new(4, envelopes.clone());
+    let mut hilbert = PackedRTree::new_hilbert(4, &envelopes);
+    for i in (0..envelopes.len()).filter(|&i| removed(i)) {
+        assert!(unsorted.remove(i));
+        assert!(!unsorted.remove(i));
+        assert!(hilbert.remove(i));
+        assert!(!hilbert.remove(i));
+    }
+    assert!(!hilbert.remove(envelopes.len()));
+
+    let mut results: Vec<usize> = unsorted
+        .query_rect_ranges(&query_rect)
+        .into_iter()
+        .flatten()
+        .collect();
+    results.sort_unstable();
+    assert_eq!(results, brute_results);
+    assert_eq!(get_rtree_intersections(query_rect, unsorted), brute_results);
+
+    assert_eq!(hilbert.envelope(), Rectangle::of(&envelopes));
+    hilbert.refit();
+    assert_eq!(hilbert.envelope(), Rectangle::of(&remaining));
+    assert_eq!(get_rtree_intersections(query_rect, hilbert), brute_results);
+
+    // Subtrees without removed leaves are still reported as ranges.
+    let mut unsorted = PackedRTreeUnsorted::new(4, envelopes.clone());
+    unsorted.remove(5);
+    let results = unsorted.query_rect_ranges(&unsorted.envelope());
+    assert!(results.iter().any(|result| result.len() > 4));
+    let results: Vec<usize> = results.into_iter().flatten().collect();
+    assert_eq!(results.len(), envelopes.len() - 1);
+    assert!(!results.contains(&5));
+}
+
+#[test]
+fn test_remove_only_item() {
+    let rect = Rectangle::new((1., 1.).into(), (2., 2.).into());
+    let point = Coordinate::new(0., 0.);
+
+    let mut unsorted = PackedRTreeUnsorted::new(4, vec![rect]);
+    let mut hilbert = PackedRTree::new_hilbert(4, &[rect]);
+    assert_eq!(unsorted.nearest(point, 1), vec![0]);
+    assert_eq!(hilbert.nearest(point, 1), vec![0]);
+    assert!(unsorted.remove(0));
+    assert!(hilbert.remove(0));
+    assert!(unsorted.is_empty());
+    assert!(hilbert.is_empty());
+    assert_eq!(unsorted.nearest(point, 1), Vec::<usize>::new());
+    assert_eq!(hilbert.nearest(point, 1), Vec::<usize>::new());
+
+    // Removing every leaf of a taller tree also empties it.
+    let envelopes = get_envelopes();
+    let mut hilbert = PackedRTree::new_hilbert(4, &envelopes);
+    for i in 0..envelopes.len() {
+        assert!(!hilbert.is_empty());
+        hilbert.remove(i);
+    }
+    assert!(hilbert.is_empty());
+    assert_eq!(hilbert.nearest(point, 1), Vec::<usize>::new());
+}
+
+#[test]
+fn test_update_envelope() {
+    let mut envelopes = get_envelopes();
+    let mut unsorted = PackedRTreeUnsorted::new(4, envelopes.clone());
+    let mut hilbert = PackedRTree::new_hilbert(4, &envelopes);
+    assert!(!hilbert.refit().should_rebuild());
+
+    // Move everything a little, and check queries before and after refit.
+    for (i, envelope) in envelopes.iter_mut().enumerate() {
+        *envelope = Rectangle {
+            x_min: envelope.x_min + 3.,
+            y_min: envelope.y_min - 2.,
+            x_max: envelope.x_max + 3.,
+            y_max: envelope.y_max - 2.,
+        };
+        assert!(unsorted.update_envelope(i, *envelope));
+        assert!(hilbert.update_envelope(i, *envelope));
+    }
+    let n = envelopes.len();
+    assert!(!unsorted.update_envelope(n, envelopes[0]));
+    assert!(!hilbert.update_envelope(n, envelopes[0]));
+    let query_rect = Rectangle::new((40., 40.).into(), (60., 60.).into());
+    let brute_results = find_brute_intersections(&query_rect, &envelopes);
+    assert_eq!(get_rtree_intersections(query_rect, hilbert), brute_results);
+    unsorted.refit();
+    assert_eq!(unsorted.envelope(), Rectangle::of(&envelopes));
+    assert_eq!(get_rtree_intersections(query_rect, unsorted), brute_results);
+
+    // Scattering the leaves makes a rebuild worthwhile.
+    let mut hilbert = PackedRTree::new_hilbert(4, &envelopes);
+    for i in 0..n {
+        hilbert.update_envelope(i, envelopes[n - 1 - i]);
+    }
+    assert!(hilbert.refit().should_rebuild());
+}
+
+#[test]
+fn test_update_empty_envelope() {
+    let r1 = Rectangle::new((0., 0.).into(), (1., 1.).into());
+    let r2 = Rectangle::new((5., 5.).into(), (6., 6.).into());
+    let mut unsorted = PackedRTreeUnsorted::new(4, vec![r1, Rectangle::new_empty()]);
+    let mut hilbert = PackedRTree::new_hilbert(4, &[r1, Rectangle::new_empty()]);
+
+    // A leaf that was empty when built can be given an envelope, and emptied again.
+    unsorted.update_envelope(1, r2);
+    hilbert.update_envelope(1, r2);
+    assert_eq!(unsorted.query_rect(&r2), vec![1]);
+    assert_eq!(hilbert.query_rect(&r2), vec![1]);
+    unsorted.update_envelope(1, Rectangle::new_empty());
+    assert!(!unsorted.remove(1));
+    unsorted.refit();
+    assert_eq!(unsorted.query_rect(&r2), Vec::<usize>::new());
+    assert_eq!(unsorted.envelope(), r1);
+}
+
+fn get_rtree_self_intersections(rtree: impl RTree) -> Vec<(usize, usize)> {
+    let mut rtree_results = rtree.query_self_intersections();
+    rtree_results.sort_unstable();
+    rtree_results
+}
+
+#[test]
+fn test_self_intersections() {
+    let envelopes: Vec<Rectangle> = get_envelopes();
+    let brute_results = find_brute_self_intersections(&envelopes);
+
+    let results = get_rtree_self_intersections(PackedRTreeUnsorted::new(16, envelopes.clone()));
+    assert_eq!(results, brute_results);
+
+    let results = get_rtree_self_intersections(PackedRTreeAutoSimd::new(16, &envelopes));
+    assert_eq!(results, brute_results);
+
+    let results = get_rtree_self_intersections(PackedRTreeSoa::new(16, &envelopes));
+    assert_eq!(results, brute_results);
+
+    let results = get_rtree_self_intersections(RStarTree::new(4, &envelopes));
+    assert_eq!(results, brute_results);
+
+    let results = get_rtree_self_intersections(PackedRTreeF32::new(4, envelopes.clone()));
+    assert_eq!(results, brute_results);
+
+    let results = get_rtree_self_intersections(PackedRTreeQuantized::new(4, envelopes.clone()));
+    assert_eq!(results, brute_results);
+
+    let results = get_rtree_self_intersections(PackedRTree::new_hilbert(16, &envelopes));
+    assert_eq!(results, brute_results);
+
+    let results = get_rtree_self_intersections(PackedRTree::new_omt(16, &envelopes));
+    assert_eq!(results, brute_results);
+
+    let results = get_rtree_self_intersections(PackedRTree::new_omt(4, &envelopes));
+    assert_eq!(results, brute_results);
+
+    let results = get_rtree_self_intersections(PackedRTree::new_str(16, &envelopes));
+    assert_eq!(results, brute_results);
+
+    let results = get_rtree_self_intersections(PackedRTreeSortedAutoSimd::new_omt(4, &envelopes));
+    assert_eq!(results, brute_results);
+}
+
+#[test]
+fn test_self_intersections_single_item() {
+    let envelopes = vec![Rectangle::new((0., 0.).into(), (1., 1.).into())];
+    assert!(PackedRTreeUnsorted::new(4, envelopes.clone())
+        .query_self_intersections()
+        .is_empty());
+    assert!(PackedRTreeAutoSimd::new(4, &envelopes)
+        .query_self_intersections()
+        .is_empty());
+}
+
+#[test]
+fn test_rtree_intersections() {
+    let mut envelopes1 = get_envelopes();
+    let n_envs = envelopes1.len();
+    let envelopes2 = envelopes1.split_off(2 * envelopes1.len() / 3);
+    assert_eq!(envelopes1.len() + envelopes2.len(), n_envs);
+    let brute_results = find_brute_cross_intersections(&envelopes1, &envelopes2);
+
+    let rtree1 = PackedRTreeUnsorted::new(16, envelopes1.clone());
+    let rtree2 = PackedRTreeUnsorted::new(4, envelopes2.clone());
+    let mut results = rtree1.query_rtree_intersections(&rtree2);
+    results.sort_unstable();
+    assert_eq!(results, brute_results);
+
+    let rtree1 = PackedRTree::new_hilbert(8, &envelopes1);
+    let rtree2 = PackedRTreeAutoSimd::new(16, &envelopes2);
+    let mut results = rtree1.query_rtree_intersections(&rtree2);
+    results.sort_unstable();
+    assert_eq!(results, brute_results);
+
+    let rtree1 = PackedRTreeAutoSimd::new(2, &envelopes1);
+    let rtree2 = PackedRTree::new_omt(16, &envelopes2);
+    let mut results = rtree1.query_rtree_intersections(&rtree2);
+    results.sort_unstable();
+    assert_eq!(results, brute_results);
+
+    let rtree1 = RStarTree::new(4, &envelopes1);
+    let rtree2 = RStarTree::new(8, &envelopes2);
+    let mut results = rtree1.query_rtree_intersections(&rtree2);
+    results.sort_unstable();
+    assert_eq!(results, brute_results);
+}
+
+#[test]
+fn test_rtree_intersections_with_empty() {
+    let envelopes1 = get_envelopes();
+    let rtree1 = PackedRTree::new_hilbert(16, &envelopes1);
+    let rtree2 = PackedRTreeUnsorted::new_empty();
+    assert_eq!(
+        rtree1.query_rtree_intersections(&rtree2),
+        Vec::<(usize, usize)>::new()
+    );
+    assert_eq!(
+        rtree2.query_rtree_intersections(&rtree1),
+        Vec::<(usize, usize)>::new()
+    );
+}
 
 fn find_brute_intersections(query_rect: &Rectangle, envelopes: &[Rectangle]) -> Vec<usize> {
     envelopes
@@ -242,31 +1136,43 @@ fn find_brute_intersections(query_rect: &Rectangle, envelopes: &[Rectangle]) ->
         .collect()
 }
 
-// fn find_brute_self_intersections(envelopes: &[Rectangle]) -> Vec<(usize, usize)> {
-//     let mut results = Vec::new();
-//     for (i1, e1) in envelopes.iter().copied().enumerate() {
-//         for (i2, e2) in envelopes.iter().copied().enumerate() {
-//             if i1 >= i2 {
-//                 continue;
-//             }
-//             if !e1.intersects(e2) {
-//                 continue;
-//             }
-//             results.push((i1, i2))
-//         }
-//     }
-//     results
-// }
-
-// fn find_brute_cross_intersections(
-//     envelopes1: &[Rectangle],
-//     envelopes2: &[Rectangle],
-// ) -> Vec<(usize, usize)> {
-//     type EnumEnv = (usize, Rectangle);
-//     let envelopes1: Vec<EnumEnv> = envelopes1.iter().copied().enumerate().collect();
-//     let envelopes2: Vec<EnumEnv> = envelopes2.iter().copied().enumerate().collect();
-//     iproduct!(envelopes1, envelopes2)
-//         .filter(|((_, e1), (_, e2))| e1.intersects(*e2))
-//         .map(|((i1, _), (i2, _))| (i1, i2))
-//         .collect()
-// }
+fn find_brute_nearest_distances(point: Coordinate, k: usize, envelopes: &[Rectangle]) -> Vec<f64> {
+    let mut distances: Vec<f64> = envelopes
+        .iter()
+        .map(|e| e.distance_squared(point))
+        .collect();
+    distances.sort_unstable_by(|d1, d2| d1.partial_cmp(d2).unwrap());
+    distances.truncate(k);
+    distances
+}
+
+fn find_brute_self_intersections(envelopes: &[Rectangle]) -> Vec<(usize, usize)> {
+    let mut results = Vec::new();
+    for (i1, e1) in envelopes.iter().enumerate() {
+        for (i2, e2) in envelopes.iter().enumerate() {
+            if i1 >= i2 {
+                continue;
+            }
+            if !e1.intersects(e2) {
+                continue;
+            }
+            results.push((i1, i2))
+        }
+    }
+    results
+}
+
+fn find_brute_cross_intersections(
+    envelopes1: &[Rectangle],
+    envelopes2: &[Rectangle],
+) -> Vec<(usize, usize)> {
+    let mut results = Vec::new();
+    for (i1, e1) in envelopes1.iter().enumerate() {
+        for (i2, e2) in envelopes2.iter().enumerate() {
+            if e1.intersects(e2) {
+                results.push((i1, i2))
+            }
+        }
+    }
+    results
+}
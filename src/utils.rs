@@ -1,3 +1,5 @@
+use std::cmp::Ordering;
+
 use crate::{Coordinate, Rectangle};
 
 pub fn rectangles_from_coordinates(coords: &[Coordinate]) -> Vec<Rectangle> {
@@ -37,3 +39,23 @@ pub(crate) fn divup(dividend: usize, divisor: usize) -> usize {
         _ => quotient + 1,
     }
 }
+
+pub(crate) fn total_cmp(x1: f64, x2: f64) -> Ordering {
+    // TODO: Use total_cmp when stabilized
+    // x1.total_cmp(&x2)
+    match x1.partial_cmp(&x2) {
+        Some(ord) => ord,
+        None => {
+            // One or both is a NaN
+            if x1.is_nan() {
+                if x2.is_nan() {
+                    Ordering::Equal
+                } else {
+                    Ordering::Greater
+                }
+            } else {
+                Ordering::Less
+            }
+        }
+    }
+}